@@ -0,0 +1,47 @@
+//! Settings kept in a `.config.json` file next to the log file, so that
+//! everyone sharing the log file totals up time the same way.
+
+use std::{fs, io, path::Path};
+
+use color_eyre::eyre::{self, eyre, Context};
+use serde::Deserialize;
+
+use crate::{parse_time_of_day, Calendar, CalendarArgs, WeekStart};
+
+/// The contents of the config file. Anything left out is taken from the
+/// command line or the environment, or else left as the default.
+#[derive(Default, Deserialize)]
+pub struct Config {
+    week_start: Option<WeekStart>,
+    /// A time of day like `04:00`.
+    day_start: Option<String>,
+}
+
+impl Config {
+    /// Read the config file at `path`. A missing config file is an empty one.
+    pub fn open(path: &Path) -> eyre::Result<Config> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .wrap_err(eyre!("Failed to parse config file at `{}`", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).wrap_err(eyre!("Failed to read config file at `{}`", path.display())),
+        }
+    }
+
+    /// When days and weeks start, going by `args` first and then by this.
+    pub fn calendar(&self, args: &CalendarArgs) -> eyre::Result<Calendar> {
+        let day_start = match (args.day_start, &self.day_start) {
+            (Some(day_start), _) => day_start,
+            (None, Some(day_start)) => parse_time_of_day(day_start)
+                .wrap_err(eyre!("Invalid `day_start` in the config file"))?,
+            (None, None) => time::Time::MIDNIGHT,
+        };
+        Ok(Calendar {
+            week_start: args
+                .week_start
+                .or(self.week_start)
+                .unwrap_or(WeekStart::Sunday),
+            day_start,
+        })
+    }
+}
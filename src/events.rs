@@ -0,0 +1,312 @@
+//! Storing the log as a list of changes rather than as one document.
+//!
+//! An event log file has one JSON object per line. The first line is
+//! [`HEADER`], which marks the file as an event log. The second is a snapshot
+//! of the whole log, and each line after it is an [`Event`] that changes it,
+//! so a change only needs a few lines to be appended to the file instead of
+//! rewriting all of it. `compact` folds all the events back into a single
+//! snapshot.
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use color_eyre::eyre::{self, bail, eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{
+    migrations,
+    repair::{self, Salvaged},
+    storage::{Backend, Storage},
+    Log, Session,
+};
+
+/// The first line of an event log.
+pub const HEADER: &str = r#"{"format":"ttrk-events"}"#;
+
+/// The log as an event log.
+#[derive(Default)]
+pub struct EventLog {
+    /// Whether the event log can be appended to as it is. It cannot if its
+    /// last line is incomplete, or if its last snapshot is from an older
+    /// version, since then the events written by this version would not fit
+    /// with it.
+    appendable: bool,
+}
+
+impl Backend for EventLog {
+    fn storage(&self) -> Storage {
+        Storage::Events
+    }
+
+    fn read(&mut self, path: &Path) -> eyre::Result<Log> {
+        let content = fs::read_to_string(path)
+            .wrap_err(eyre!("Failed to read log file at `{}`", path.display()))?;
+        let (log, version) = replay(&content)?;
+        self.appendable = content.ends_with('\n') && version == migrations::VERSION;
+        Ok(log)
+    }
+
+    fn update(&mut self, path: &Path, before: &Log, after: &Log) -> eyre::Result<bool> {
+        if !self.appendable {
+            return Ok(false);
+        }
+        let Some(lines) = diff(before, after)? else {
+            return Ok(false);
+        };
+        let mut file = File::options()
+            .append(true)
+            .open(path)
+            .wrap_err(eyre!("Failed to open log file at `{}`", path.display()))?;
+        file.write_all(lines.as_bytes())
+            .and_then(|()| file.sync_data())
+            .wrap_err(eyre!("Failed to write to log file at `{}`", path.display()))?;
+        Ok(true)
+    }
+
+    fn create(&mut self, path: &Path, log: &Log) -> eyre::Result<()> {
+        fs::write(path, format!("{}\n{}", HEADER, snapshot(log)?))?;
+        self.appendable = true;
+        Ok(())
+    }
+}
+
+/// A change to the log.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Event {
+    /// A session was begun, or the current session was changed. It becomes
+    /// the current session, and if it was a completed session, it no longer
+    /// is.
+    Begin { session: Session },
+    /// The current session was completed.
+    End { session: Session },
+    /// The current session was thrown away.
+    Cancel,
+    /// A completed session was added or changed.
+    Edit { session: Session },
+    /// A completed session was deleted.
+    Delete { id: String },
+}
+
+impl Event {
+    fn apply(self, log: &mut Log) {
+        match self {
+            Event::Begin { session } => {
+                log.completed.retain(|other| other.id != session.id);
+                log.current = Some(session);
+            }
+            Event::End { session } => {
+                if log
+                    .current
+                    .as_ref()
+                    .is_some_and(|current| current.id == session.id)
+                {
+                    log.current = None;
+                }
+                match log
+                    .completed
+                    .iter()
+                    .position(|other| other.id == session.id)
+                {
+                    Some(index) => log.completed[index] = session,
+                    None => log.completed.push(session),
+                }
+            }
+            Event::Cancel => log.current = None,
+            Event::Edit { session } => {
+                match log
+                    .completed
+                    .iter()
+                    .position(|other| other.id == session.id)
+                {
+                    Some(index) => log.completed[index] = session,
+                    None => {
+                        let index = log
+                            .completed
+                            .partition_point(|other| other.start.0 <= session.start.0);
+                        log.completed.insert(index, session);
+                    }
+                }
+            }
+            Event::Delete { id } => log.completed.retain(|other| other.id != id),
+        }
+    }
+}
+
+/// The line after the header, which the events build on. Event logs from
+/// older versions may have them further along too, each replacing the whole
+/// log.
+#[derive(Serialize)]
+#[serde(tag = "op", rename = "snapshot")]
+struct Snapshot<'a> {
+    version: u64,
+    log: &'a Log,
+}
+
+/// A line with a snapshot of `log`, including the newline.
+fn snapshot(log: &Log) -> eyre::Result<String> {
+    let mut line = serde_json::to_string(&Snapshot {
+        version: migrations::VERSION,
+        log,
+    })?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Lines with the events that turn `before` into `after`, or `None` if that
+/// cannot be told with events.
+fn diff(before: &Log, after: &Log) -> eyre::Result<Option<String>> {
+    let mut events = vec![];
+    let before_current = before.current.as_ref().map(|current| &current.id);
+    for session in &before.completed {
+        let kept = after.sessions().any(|other| other.id == session.id);
+        if !kept {
+            events.push(Event::Delete {
+                id: session.id.clone(),
+            });
+        }
+    }
+    for session in &after.completed {
+        if before_current == Some(&session.id) {
+            events.push(Event::End {
+                session: session.clone(),
+            });
+            continue;
+        }
+        let old = before.completed.iter().find(|old| old.id == session.id);
+        if !same(old, Some(session))? {
+            events.push(Event::Edit {
+                session: session.clone(),
+            });
+        }
+    }
+    match after.current {
+        Some(ref session) => {
+            if !same(before.current.as_ref(), Some(session))? {
+                events.push(Event::Begin {
+                    session: session.clone(),
+                });
+            }
+        }
+        None => {
+            if let Some(id) = before_current {
+                if !after.completed.iter().any(|other| other.id == *id) {
+                    events.push(Event::Cancel);
+                }
+            }
+        }
+    }
+
+    let mut lines = String::new();
+    for event in &events {
+        lines.push_str(&serde_json::to_string(event)?);
+        lines.push('\n');
+    }
+    // Some changes, like reordering sessions in `fixup`, cannot be told with
+    // events. Rather than risk getting those wrong, check that the events do
+    // what they should, and have the log file rewritten if not.
+    let mut replayed = before.clone();
+    for event in events {
+        event.apply(&mut replayed);
+    }
+    if serde_json::to_value(&replayed)? != serde_json::to_value(after)? {
+        return Ok(None);
+    }
+    Ok(Some(lines))
+}
+
+fn same(a: Option<&Session>, b: Option<&Session>) -> eyre::Result<bool> {
+    Ok(serde_json::to_value(a)? == serde_json::to_value(b)?)
+}
+
+/// Rebuild the log by replaying an event log. Also returns the version of its
+/// last snapshot.
+fn replay(content: &str) -> eyre::Result<(Log, u64)> {
+    let mut log = None;
+    let mut version = 0;
+    let mut lines = content.lines().enumerate().peekable();
+    let mut position = 0;
+    while let Some((index, line)) = lines.next() {
+        let number = index + 1;
+        let line_start = position;
+        position += line.len() + 1;
+        match replay_line(&mut log, &mut version, line) {
+            Ok(()) => {}
+            // A line cut short by a crash while it was being appended is not
+            // worth losing the rest of the log over. The next write will
+            // rewrite the log file without it.
+            Err(e) if lines.peek().is_none() && !content.ends_with('\n') && log.is_some() => {
+                warn!(
+                    "Ignoring incomplete last line {} of the log file: {}",
+                    number, e
+                )
+            }
+            Err(e) => {
+                return Err(repair::parse_error(line_start, e)
+                    .wrap_err(eyre!("Failed to read line {} of the log file", number)))
+            }
+        }
+    }
+    let log = log.ok_or_else(|| eyre!("The log file is empty."))?;
+    Ok((log, version))
+}
+
+fn replay_line(log: &mut Option<Log>, version: &mut u64, line: &str) -> eyre::Result<()> {
+    if log.is_none() && line == HEADER {
+        return Ok(());
+    }
+    let mut value: Value = serde_json::from_str(line)?;
+    if value.get("op").and_then(Value::as_str) == Some("snapshot") {
+        let Some(Value::Object(mut snapshot)) = value.get_mut("log").map(Value::take) else {
+            bail!("A snapshot must contain a log.");
+        };
+        if let Some(version) = value.get_mut("version").map(Value::take) {
+            snapshot.insert("version".to_string(), version);
+        }
+        *version = snapshot.get("version").and_then(Value::as_u64).unwrap_or(0);
+        let snapshot = migrations::migrate(Value::Object(snapshot))?;
+        *log = Some(serde_json::from_value(snapshot)?);
+        return Ok(());
+    }
+    let Some(log) = log else {
+        bail!("An event log must start with a snapshot.");
+    };
+    let event: Event = serde_json::from_value(value).wrap_err(eyre!("Invalid event"))?;
+    event.apply(log);
+    Ok(())
+}
+
+/// Replay as much of a broken event log as possible, skipping the lines that
+/// cannot be replayed. A broken snapshot is salvaged like a broken JSON log.
+pub fn salvage(content: &str) -> Salvaged {
+    let mut log = None;
+    let mut version = 0;
+    let mut problems = vec![];
+    for (index, line) in content.lines().enumerate() {
+        let number = index + 1;
+        let Err(e) = replay_line(&mut log, &mut version, line) else {
+            continue;
+        };
+        if log.is_none() || line.contains(r#""op":"snapshot""#) {
+            let salvaged = repair::salvage(line);
+            log = Some(salvaged.log);
+            problems.extend(
+                salvaged
+                    .problems
+                    .into_iter()
+                    .map(|problem| format!("Line {}: {}", number, problem)),
+            );
+        } else {
+            problems.push(format!("Skipped line {}: {:#}", number, e));
+        }
+    }
+    Salvaged {
+        log: log.unwrap_or_default(),
+        problems,
+    }
+}
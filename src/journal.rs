@@ -0,0 +1,205 @@
+//! A journal of changes to the log, for undo and redo.
+
+use std::{fs, io, path::PathBuf};
+
+use color_eyre::eyre::{self, bail, eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{storage, Time};
+
+/// How many changes to remember. Every change keeps two copies of the log, so
+/// this should not be too big.
+const JOURNAL_LENGTH: usize = 20;
+
+/// The most recent changes to the log, kept in a `.journal` file next to the
+/// log file.
+///
+/// Each change is kept as a snapshot of the log before and after it, so that
+/// any change can be undone, however it was made. Undoing a change does not
+/// forget it until a new change is made, so that it can be redone. The
+/// journal is saved before the log is written.
+pub struct Journal {
+    path: PathBuf,
+    contents: Contents,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Contents {
+    /// Oldest first.
+    entries: Vec<Entry>,
+    /// How many of the newest entries have been undone.
+    undone: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub time: Time,
+    /// The command line that made the change.
+    pub command: String,
+    before: Value,
+    after: Value,
+}
+
+impl Journal {
+    /// Read the journal at `path`. A missing journal is an empty one, and so
+    /// is a broken one, since it is not worth refusing to work over.
+    pub fn open(path: PathBuf) -> eyre::Result<Journal> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!(
+                    "Failed to parse the journal at `{}`, starting a new one: {}",
+                    path.display(),
+                    e
+                );
+                Contents::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Contents::default(),
+            Err(e) => {
+                return Err(e).wrap_err(eyre!("Failed to read journal at `{}`", path.display()))
+            }
+        };
+        Ok(Journal { path, contents })
+    }
+
+    /// Remember that `command` changed the log from `before` to `after`. Any
+    /// undone changes cannot be redone after this.
+    pub fn record(&mut self, time: Time, command: String, before: Value, after: Value) {
+        self.settle(&before);
+        let contents = &mut self.contents;
+        let len = contents.entries.len() - contents.undone;
+        contents.entries.truncate(len);
+        contents.undone = 0;
+        contents.entries.push(Entry {
+            time,
+            command,
+            before,
+            after,
+        });
+        if contents.entries.len() > JOURNAL_LENGTH {
+            let extra = contents.entries.len() - JOURNAL_LENGTH;
+            contents.entries.drain(..extra);
+        }
+    }
+
+    /// Undo the newest change that has not been undone, given the log as it
+    /// is now. Returns the change and the log as it was before it, or `None`
+    /// if there is nothing left to undo.
+    pub fn undo(&mut self, current: &Value) -> eyre::Result<Option<(&Entry, Value)>> {
+        self.settle(current);
+        let contents = &mut self.contents;
+        let Some(index) = contents.entries.len().checked_sub(contents.undone + 1) else {
+            return Ok(None);
+        };
+        let entry = &contents.entries[index];
+        if entry.after != *current {
+            bail!(
+                "The log was changed since `{}`, so it cannot be undone.",
+                entry.command
+            );
+        }
+        contents.undone += 1;
+        Ok(Some((entry, entry.before.clone())))
+    }
+
+    /// Redo the oldest change that was undone, given the log as it is now.
+    /// Returns the change and the log as it was after it, or `None` if there
+    /// is nothing to redo.
+    pub fn redo(&mut self, current: &Value) -> eyre::Result<Option<(&Entry, Value)>> {
+        self.settle(current);
+        let contents = &mut self.contents;
+        if contents.undone == 0 {
+            return Ok(None);
+        }
+        let entry = &contents.entries[contents.entries.len() - contents.undone];
+        if entry.before != *current {
+            bail!(
+                "The log was changed since `{}` was undone, so it cannot be redone.",
+                entry.command
+            );
+        }
+        contents.undone -= 1;
+        Ok(Some((entry, entry.after.clone())))
+    }
+
+    /// Make the journal agree with the log as it is now, if ttrk was stopped
+    /// after saving the journal but before writing the log. A change, undo
+    /// or redo that never made it to the log is taken back.
+    fn settle(&mut self, current: &Value) {
+        let contents = &mut self.contents;
+        let len = contents.entries.len();
+        if let Some(entry) = len
+            .checked_sub(contents.undone + 1)
+            .map(|index| &contents.entries[index])
+        {
+            // A change or redo, which leaves the log as it was before.
+            if entry.before == *current && entry.after != *current {
+                contents.undone += 1;
+                return;
+            }
+        }
+        if contents.undone > 0 {
+            // An undo, which leaves the log as it was after.
+            let entry = &contents.entries[len - contents.undone];
+            if entry.after == *current && entry.before != *current {
+                contents.undone -= 1;
+            }
+        }
+    }
+
+    /// All remembered changes, newest first, with whether they were undone.
+    pub fn entries(&self) -> impl Iterator<Item = (&Entry, bool)> {
+        let undone_from = self.contents.entries.len() - self.contents.undone;
+        self.contents
+            .entries
+            .iter()
+            .enumerate()
+            .rev()
+            .map(move |(index, entry)| (entry, index >= undone_from))
+    }
+
+    pub fn save(&self) -> eyre::Result<()> {
+        let content =
+            serde_json::to_string(&self.contents).wrap_err(eyre!("Failed to write to journal"))?;
+        storage::replace_file(&self.path, &content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn journal() -> Journal {
+        let mut journal = Journal {
+            path: PathBuf::new(),
+            contents: Contents::default(),
+        };
+        let time = Time(datetime!(2023-03-15 12:00 UTC));
+        journal.record(time, "first".to_string(), json!(0), json!(1));
+        journal.record(time, "second".to_string(), json!(1), json!(2));
+        journal
+    }
+
+    #[test]
+    fn change_that_never_made_it() {
+        let mut journal = journal();
+        // The log was left as it was before `second`.
+        let (entry, log) = journal.undo(&json!(1)).unwrap().unwrap();
+        assert_eq!(entry.command, "first");
+        assert_eq!(log, json!(0));
+    }
+
+    #[test]
+    fn undo_that_never_made_it() {
+        let mut journal = journal();
+        journal.undo(&json!(2)).unwrap();
+        // The log was left as it was after `second`.
+        let (entry, log) = journal.undo(&json!(2)).unwrap().unwrap();
+        assert_eq!(entry.command, "second");
+        assert_eq!(log, json!(1));
+    }
+}
@@ -1,70 +1,745 @@
 use std::{
     cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
     env,
     fmt::Write as _,
-    fs::{self, File},
+    fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Command,
+    str::FromStr,
 };
 
 use color_eyre::eyre::{self, bail, eyre, Context};
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use dirs::home_dir;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
 use tempfile::{tempdir, NamedTempFile};
-use time::{format_description::FormatItem, macros::format_description, Duration, OffsetDateTime};
-use tracing::{error, info};
+use time::{
+    format_description::FormatItem, macros::format_description, Date, Duration, Month,
+    OffsetDateTime, PrimitiveDateTime, UtcOffset,
+};
+use tracing::{error, warn};
 use tracing_subscriber::EnvFilter;
 
+mod config;
+mod events;
+mod journal;
+mod migrations;
+mod output;
+mod repair;
+mod report;
+mod sqlite;
+mod storage;
+
+use config::Config;
+use journal::Journal;
+use report::Grouping;
+use storage::{LogFile, Query, Storage};
+
 #[derive(Clone, Debug, Parser)]
 #[clap(propagate_version = true)]
 #[clap(author, about, version)]
 struct Cli {
     #[clap(subcommand)]
-    command: Commands,
-    /// The JSON log file to output sessions to.
+    command: AnyCommand,
+    /// The log file to output sessions to.
     ///
     /// By default this is located at `~/.ttrk.json`.
     #[clap(short = 'l', long)]
     logfile: Option<PathBuf>,
+    /// How many backups of the log file to keep.
+    ///
+    /// Before the log is changed, the old log file is copied to
+    /// `<logfile>.bak.1`, and older backups are moved along to `.bak.2` and so
+    /// on, up to this many.
+    #[clap(long, global = true, default_value_t = 10)]
+    backups: usize,
+    /// How to store the log file.
+    ///
+    /// By default, an existing log file is stored the way it already is, and
+    /// a new one as JSON. Given this, the log file is converted the next time
+    /// it is changed.
+    #[clap(long, global = true, value_enum)]
+    storage: Option<Storage>,
+    /// How to print what `status`, `list` and `report` show.
+    ///
+    /// JSON is meant for scripts. Its fields are described in the README, and
+    /// do not change along with the text.
+    #[clap(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    #[clap(flatten)]
+    calendar: CalendarArgs,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// When days and weeks start, as given on the command line or in the
+/// environment. These take precedence over the config file.
+#[derive(Clone, Debug, Args)]
+struct CalendarArgs {
+    /// The day weeks start on. Defaults to Sunday.
+    ///
+    /// This can also be set as `week_start` in `<logfile>.config.json`.
+    #[clap(long, global = true, value_enum, env = "TTRK_WEEK_START")]
+    week_start: Option<WeekStart>,
+    /// The time of day days start at. Defaults to midnight.
+    ///
+    /// For example, with `04:00`, time spent past midnight but before 4 AM
+    /// counts toward the day before. This can also be set as `day_start` in
+    /// `<logfile>.config.json`.
+    #[clap(
+        long,
+        global = true,
+        value_parser = parse_time_of_day,
+        env = "TTRK_DAY_START"
+    )]
+    day_start: Option<time::Time>,
+}
+
+/// When days and weeks start, for totalling up time spent in them.
+#[derive(Clone, Debug)]
+struct Calendar {
+    week_start: WeekStart,
+    day_start: time::Time,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum WeekStart {
+    Sunday,
+    Monday,
+    /// Weeks start on Monday and are numbered as in ISO 8601, where the first
+    /// week of a year is the one with its first Thursday.
+    Iso,
+}
+
+/// `repair` is kept apart from the other commands, as it works on a log file
+/// that cannot be read the usual way.
+#[derive(Clone, Debug, Subcommand)]
+enum AnyCommand {
+    #[clap(flatten)]
+    Log(Commands),
+    #[clap(flatten)]
+    Lookup(Lookup),
+    /// Recover what can be recovered from a broken log file.
+    ///
+    /// The recovered log only replaces the log file once you agree to it.
+    Repair,
 }
 
 #[derive(Clone, Debug, Subcommand)]
 enum Commands {
     /// Begin a session.
-    Begin,
+    Begin {
+        /// The project this session is part of.
+        #[clap(short = 'p', long)]
+        project: Option<String>,
+        /// A tag to give this session. May be given more than once.
+        #[clap(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// When the session began, if not now.
+        ///
+        /// Either a time like `14:05` or `2026-10-15 09:00`, or a time before
+        /// now like `-15m` or `10 minutes ago`. A time of day alone that is
+        /// later than now means yesterday.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
     /// End a session, giving a message of what was done.
     End {
+        /// What was done. May be left out if the session has a draft
+        /// message, e.g. from `continue`.
+        #[clap(value_parser)]
+        message: Option<String>,
+        /// A tag to add to this session. May be given more than once.
+        #[clap(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// When the session ended, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
+    /// End the current session and immediately begin the next one.
+    Switch {
+        /// What was done in the session being ended. See `end`.
+        #[clap(value_parser)]
+        message: Option<String>,
+        /// The project the new session is part of.
+        #[clap(short = 'p', long)]
+        project: Option<String>,
+        /// A tag to give the new session. May be given more than once.
+        #[clap(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// When to switch sessions, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
+    /// Begin a session continuing on from the most recent completed one.
+    ///
+    /// By default this begins a new session with the project, tags and
+    /// message (as a draft) of the most recent completed session.
+    Continue {
+        /// Reopen the most recent completed session as the current one
+        /// instead. The time since it ended is counted as a break.
+        #[clap(short = 'r', long, conflicts_with_all = ["project", "tags"])]
+        reopen: bool,
+        /// The project the new session is part of, instead of the previous
+        /// session's.
+        #[clap(short = 'p', long)]
+        project: Option<String>,
+        /// A tag to give the new session, instead of the previous session's.
+        /// May be given more than once.
+        #[clap(short = 't', long = "tag")]
+        tags: Vec<String>,
+        /// When to continue, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
+    /// Log a completed session after the fact.
+    Add {
+        /// What was done.
         #[clap(value_parser)]
         message: String,
+        /// When the session began. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        from: TimeSpec,
+        /// When the session ended. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        to: TimeSpec,
+        /// The project this session is part of.
+        #[clap(short = 'p', long)]
+        project: Option<String>,
+        /// A tag to give this session. May be given more than once.
+        #[clap(short = 't', long = "tag")]
+        tags: Vec<String>,
+    },
+    /// Change a completed session.
+    Edit {
+        /// The ID of the session, or a unique prefix of it.
+        id: String,
+        /// When the session began. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        start: Option<TimeSpec>,
+        /// When the session ended. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        end: Option<TimeSpec>,
+        /// What was done.
+        #[clap(short = 'm', long)]
+        message: Option<String>,
+    },
+    /// Change the message of the most recent completed session.
+    Amend {
+        /// The new message.
+        #[clap(value_parser, required_unless_present = "edit")]
+        message: Option<String>,
+        /// Edit the old message in your `$EDITOR` instead.
+        #[clap(short = 'e', long, conflicts_with = "message")]
+        edit: bool,
+    },
+    /// Split a completed session in two.
+    Split {
+        /// The ID of the session, or a unique prefix of it.
+        id: String,
+        /// Where to split the session. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: TimeSpec,
+        /// What was done in the second session. By default this is the same
+        /// as the first session.
+        #[clap(value_parser)]
+        message: Option<String>,
+    },
+    /// Merge two neighbouring completed sessions into one.
+    ///
+    /// Any time between them is counted as a break.
+    Merge {
+        /// The ID of one session, or a unique prefix of it.
+        first: String,
+        /// The ID of the other session, or a unique prefix of it.
+        second: String,
+        /// Which of the sessions' messages to keep.
+        #[clap(long, value_enum, default_value_t = KeepMessage::Both)]
+        keep: KeepMessage,
+        /// A new message for the merged session instead.
+        #[clap(short = 'm', long, conflicts_with = "keep")]
+        message: Option<String>,
+    },
+    /// Delete a completed session.
+    Delete {
+        /// The ID of the session, or a unique prefix of it.
+        id: String,
     },
     /// Cancel the current session.
-    Cancel,
-    /// Get the status of the current session and of the log overall.
-    Status,
-    /// Show all sessions, completed and current.
-    List,
+    Cancel {
+        /// When the session was canceled, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
+    /// Pause the current session, e.g. to take a break.
+    Pause {
+        /// When the break began, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
+    /// Resume the current session after a break.
+    Resume {
+        /// When the break ended, if not now. See `begin --at`.
+        #[clap(long, allow_hyphen_values = true)]
+        at: Option<TimeSpec>,
+    },
     /// Fix up the log file in your `$EDITOR`.
     Fixup,
+    /// Fold the changes in a log file stored as events into a single
+    /// snapshot.
+    Compact,
+    /// Convert the log file to be stored another way.
+    Migrate {
+        /// How to store the log file from now on.
+        #[clap(long, value_enum)]
+        to: Storage,
+    },
+    /// List or restore backups of the log file.
+    Backups {
+        #[clap(subcommand)]
+        command: BackupsCommand,
+    },
+    /// Undo the last change to the log.
+    Undo,
+    /// Redo the last change that was undone.
+    Redo,
+    /// Show the most recent changes to the log, newest first.
+    History,
+}
+
+impl Commands {
+    /// Whether this command only ever looks at the log, and never changes it.
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Commands::Backups {
+                command: BackupsCommand::List
+            } | Commands::History
+        )
+    }
+}
+
+/// Commands that only look sessions up. These read just the sessions they
+/// need, with `LogFile::query`, rather than the whole log.
+#[derive(Clone, Debug, Subcommand)]
+enum Lookup {
+    /// Show the details of a session.
+    Show {
+        /// The ID of the session, or a unique prefix of it.
+        id: String,
+    },
+    /// Get the status of the current session and of the log overall.
+    Status {
+        #[clap(flatten)]
+        filter: Filter,
+    },
+    /// Show all sessions, completed and current.
+    List {
+        #[clap(flatten)]
+        filter: Filter,
+    },
+    /// Total the time spent in completed sessions over each day, week, month
+    /// or year.
+    Report {
+        /// What periods to total the time spent over.
+        #[clap(long, value_enum, default_value_t = Grouping::Day)]
+        by: Grouping,
+        #[clap(flatten)]
+        filter: Filter,
+    },
     /// Export to CSV
-    Csv,
+    Csv {
+        #[clap(flatten)]
+        filter: Filter,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum BackupsCommand {
+    /// Show all backups, newest first.
+    List,
+    /// Replace the log with a backup. The current log is backed up first.
+    Restore {
+        /// Which backup to restore, as shown by `backups list`.
+        number: usize,
+    },
+}
+
+/// Which message `merge` keeps.
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum KeepMessage {
+    /// The earlier session's message.
+    First,
+    /// The later session's message.
+    Second,
+    /// Both messages, joined with `; `.
+    Both,
+}
+
+/// Options for narrowing down which sessions a command looks at.
+#[derive(Clone, Debug, Default, Args)]
+struct Filter {
+    /// Only include sessions that are part of this project.
+    #[clap(short = 'p', long)]
+    project: Option<String>,
+    /// Only include sessions that have this tag. May be given more than once.
+    #[clap(short = 't', long = "tag")]
+    tags: Vec<String>,
+    /// Leave out sessions that have this tag. May be given more than once.
+    #[clap(long = "no-tag")]
+    no_tags: Vec<String>,
+    /// Only include sessions that went on after this time. Takes the same
+    /// times as `begin --at`, or a date alone, like `2026-10-01`.
+    #[clap(long, allow_hyphen_values = true)]
+    since: Option<Bound>,
+    /// Only include sessions that went on before this time. A date alone
+    /// means up to the end of that day.
+    #[clap(long, allow_hyphen_values = true)]
+    until: Option<Bound>,
+    /// Only include sessions that went on today.
+    #[clap(long, conflicts_with_all = ["week", "month"])]
+    today: bool,
+    /// Only include sessions that went on this week.
+    #[clap(long, conflicts_with = "month")]
+    week: bool,
+    /// Only include sessions that went on this month.
+    #[clap(long)]
+    month: bool,
+    /// Only include the last this many sessions.
+    #[clap(long, value_name = "N")]
+    last: Option<usize>,
+    /// Only include sessions with a message that matches this regex.
+    #[clap(long, value_name = "REGEX")]
+    grep: Option<Regex>,
+}
+
+impl Filter {
+    /// Look up the sessions this filter lets through in the log file, in
+    /// order. With `completed_only`, the current session is left out.
+    fn look_up(
+        &self,
+        logfile: &mut LogFile,
+        calendar: &Calendar,
+        completed_only: bool,
+    ) -> eyre::Result<Vec<Session>> {
+        let query = self.query(get_time()?, calendar, completed_only)?;
+        let mut selected = logfile.query(&query)?;
+        if let Some(last) = self.last {
+            selected.drain(..selected.len().saturating_sub(last));
+        }
+        Ok(selected)
+    }
+
+    /// The query for the sessions this filter lets through as of `now`, save
+    /// for `--last`.
+    fn query(
+        &self,
+        now: OffsetDateTime,
+        calendar: &Calendar,
+        completed_only: bool,
+    ) -> eyre::Result<Query> {
+        let (from, to) = self.range(now, calendar)?;
+        Ok(Query {
+            id: None,
+            project: self.project.clone(),
+            tags: self.tags.clone(),
+            no_tags: self.no_tags.clone(),
+            grep: self.grep.clone(),
+            from,
+            to,
+            now: Some(now),
+            completed_only,
+        })
+    }
+
+    /// The times this filter limits sessions to, if any. Sessions must end
+    /// after the first and start before the second.
+    fn range(
+        &self,
+        now: OffsetDateTime,
+        calendar: &Calendar,
+    ) -> eyre::Result<(Option<OffsetDateTime>, Option<OffsetDateTime>)> {
+        let period = if self.today {
+            Some(Period::day(now, calendar))
+        } else if self.week {
+            Some(Period::week(now, calendar))
+        } else if self.month {
+            Some(Period::month(now, calendar))
+        } else {
+            None
+        };
+        let since = self
+            .since
+            .map(|since| since.time_at(now, calendar.day_start))
+            .transpose()?;
+        let until = self.until.map(|until| match until {
+            Bound::Day(date) => Bound::Day(date.next_day().unwrap_or(date)),
+            until => until,
+        });
+        let until = until
+            .map(|until| until.time_at(now, calendar.day_start))
+            .transpose()?;
+        let from = since.max(period.map(|period| period.start));
+        let to = match (until, period.map(|period| period.end)) {
+            (Some(until), Some(end)) => Some(until.min(end)),
+            (until, end) => until.or(end),
+        };
+        Ok((from, to))
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct Log {
     completed: Vec<Session>,
     current: Option<Session>,
+    /// Fields ttrk does not know about, e.g. added by other tools, kept so
+    /// that they are written back as they were.
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+impl Log {
+    fn sessions(&self) -> impl Iterator<Item = &Session> {
+        self.completed.iter().chain(&self.current)
+    }
+
+    /// Carry over the fields ttrk does not know about from `old`, e.g. after
+    /// the log was rewritten from `fixup`. Sessions are matched up by ID.
+    fn keep_extra_from(&mut self, old: &Log) {
+        self.extra = old.extra.clone();
+        for session in self.completed.iter_mut().chain(&mut self.current) {
+            if let Some(old) = old.sessions().find(|old| old.id == session.id) {
+                session.extra = old.extra.clone();
+            }
+        }
+    }
+
+    /// Make up an ID that no session has yet.
+    fn new_id(&self) -> String {
+        loop {
+            let id = format!("{:06x}", fastrand::u32(..1 << 24));
+            if self.sessions().all(|session| session.id != id) {
+                return id;
+            }
+        }
+    }
+
+    /// Give an ID to every session that does not have one yet, e.g. ones from
+    /// before sessions had IDs. These are the same each time, as with
+    /// migrating from before IDs.
+    fn assign_ids(&mut self) {
+        let mut ids = self
+            .sessions()
+            .filter(|session| !session.id.is_empty())
+            .map(|session| session.id.clone())
+            .collect::<BTreeSet<_>>();
+        for session in self.completed.iter_mut().chain(&mut self.current) {
+            if session.id.is_empty() {
+                let fields = [
+                    json!(session.start),
+                    json!(session.end),
+                    json!(session.message),
+                ];
+                session.id = migrations::derived_id(fields.each_ref(), &mut ids);
+            }
+        }
+    }
+
+    /// Find a session by its ID, or a unique prefix of it. Returns the index
+    /// of the session in `completed`, or `None` if it is the current session.
+    fn find(&self, id: &str) -> eyre::Result<Option<usize>> {
+        let (index, session) = find_session(self.sessions(), id)?;
+        Ok(session.end.map(|_| index))
+    }
+
+    /// Find a completed session by its ID, or a unique prefix of it, returning
+    /// its index in `completed`.
+    fn find_completed(&self, id: &str) -> eyre::Result<usize> {
+        self.find(id)?.ok_or_else(|| {
+            eyre!(
+                "Session `{}` is the current session, which cannot be changed this way.",
+                id
+            )
+        })
+    }
+}
+
+/// Find a session out of `sessions` by its ID, or a unique prefix of it,
+/// along with where it is in `sessions`.
+fn find_session<'a>(
+    sessions: impl IntoIterator<Item = &'a Session>,
+    id: &str,
+) -> eyre::Result<(usize, &'a Session)> {
+    if id.is_empty() {
+        bail!("A session ID cannot be empty.");
+    }
+    let mut found = sessions
+        .into_iter()
+        .enumerate()
+        .filter(|(_, session)| session.id.starts_with(id));
+    match (found.next(), found.next()) {
+        (Some(found), None) => Ok(found),
+        (Some(_), Some(_)) => bail!("More than one session has an ID starting with `{}`.", id),
+        _ => bail!("There is no session with the ID `{}`.", id),
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct Session {
+    #[serde(default)]
+    id: String,
     start: Time,
     end: Option<Time>,
     message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    tags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    breaks: Vec<Break>,
+    /// Fields ttrk does not know about. See [`Log::extra`].
+    #[serde(flatten)]
+    extra: Map<String, Value>,
+}
+
+impl Session {
+    /// How long was spent on this session up to `until`, leaving out breaks.
+    fn elapsed_until(&self, until: OffsetDateTime) -> Duration {
+        let mut elapsed = until - self.start.0;
+        for br in &self.breaks {
+            let start = br.start.0.min(until);
+            let end = br.end.map_or(until, |end| end.0.min(until));
+            if end > start {
+                elapsed -= end - start;
+            }
+        }
+        elapsed
+    }
+
+    /// How long was spent on this completed session, leaving out breaks.
+    fn elapsed(&self) -> Duration {
+        self.elapsed_until(self.end.unwrap().0)
+    }
+
+    /// How much of this completed session was spent within `period`, leaving
+    /// out breaks.
+    fn elapsed_in(&self, period: Period) -> Duration {
+        let from = period.start.max(self.start.0);
+        let to = period.end.min(self.end.unwrap().0);
+        if to <= from {
+            return Duration::ZERO;
+        }
+        self.elapsed_until(to) - self.elapsed_until(from)
+    }
+
+    /// The break this session is currently paused for, if any.
+    fn paused(&self) -> Option<&Break> {
+        self.breaks.last().filter(|br| br.end.is_none())
+    }
+}
+
+/// A span of time that time spent is totalled over, from `start` up to but
+/// not including `end`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Period {
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+}
+
+impl Period {
+    /// The day `now` is in, in `now`'s time zone.
+    fn day(now: OffsetDateTime, calendar: &Calendar) -> Period {
+        let date = Period::date(now, calendar);
+        Period::days(date, date.next_day().unwrap(), now, calendar)
+    }
+
+    /// The month `now` is in.
+    fn month(now: OffsetDateTime, calendar: &Calendar) -> Period {
+        let start = Period::date(now, calendar).replace_day(1).unwrap();
+        let end = match start.month() {
+            Month::December => start
+                .replace_year(start.year() + 1)
+                .and_then(|start| start.replace_month(Month::January)),
+            month => start.replace_month(month.next()),
+        };
+        Period::days(start, end.unwrap(), now, calendar)
+    }
+
+    /// The year `now` is in.
+    fn year(now: OffsetDateTime, calendar: &Calendar) -> Period {
+        let start = Date::from_ordinal_date(Period::date(now, calendar).year(), 1).unwrap();
+        let end = start.replace_year(start.year() + 1).unwrap();
+        Period::days(start, end, now, calendar)
+    }
+
+    /// The week `now` is in.
+    fn week(now: OffsetDateTime, calendar: &Calendar) -> Period {
+        let date = Period::date(now, calendar);
+        let days_since_start = match calendar.week_start {
+            WeekStart::Sunday => date.weekday().number_days_from_sunday(),
+            WeekStart::Monday | WeekStart::Iso => date.weekday().number_days_from_monday(),
+        };
+        let start = date - Duration::days(days_since_start.into());
+        Period::days(start, start + Duration::weeks(1), now, calendar)
+    }
+
+    /// The part of this period between `from` and `to`, if given.
+    fn within(self, (from, to): (Option<OffsetDateTime>, Option<OffsetDateTime>)) -> Period {
+        Period {
+            start: from.map_or(self.start, |from| from.max(self.start)),
+            end: to.map_or(self.end, |to| to.min(self.end)),
+        }
+    }
+
+    /// The date of the day `now` is in.
+    fn date(now: OffsetDateTime, calendar: &Calendar) -> Date {
+        // Until the day starts, it is still the day before.
+        if now.time() < calendar.day_start {
+            now.date().previous_day().unwrap()
+        } else {
+            now.date()
+        }
+    }
+
+    /// The period from when the day `start` starts up to when the day `end`
+    /// does, in the same time zone as `now`.
+    ///
+    /// If `now` is in local time, each end is in the local offset in effect
+    /// then, so that days around a daylight saving time change are as long as
+    /// they really are. Otherwise both are in `now`'s offset.
+    fn days(start: Date, end: Date, now: OffsetDateTime, calendar: &Calendar) -> Period {
+        let local = UtcOffset::local_offset_at(now).is_ok_and(|local| local == now.offset());
+        let at = |date| {
+            let datetime = PrimitiveDateTime::new(date, calendar.day_start);
+            let offset = match UtcOffset::local_offset_at(datetime.assume_offset(now.offset())) {
+                Ok(offset) if local => offset,
+                _ => now.offset(),
+            };
+            datetime.assume_offset(offset)
+        };
+        Period {
+            start: at(start),
+            end: at(end),
+        }
+    }
+}
+
+/// A pause within a session. Breaks are kept in order and do not overlap.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Break {
+    start: Time,
+    end: Option<Time>,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -73,6 +748,7 @@ struct Session {
 struct Time(#[serde(with = "time::serde::rfc3339")] pub OffsetDateTime);
 
 const TIMESTAMP_FMT: &[FormatItem] = format_description!("[month]-[day]-[year] [hour]:[minute]:[second] (UTC[offset_hour sign:mandatory]:[offset_second])");
+const TIME_ON_AT_FMT: &[FormatItem] = format_description!("on [month]-[day]-[year] at [hour]:[minute]:[second] (UTC[offset_hour sign:mandatory]:[offset_second])");
 const CSV_TIMESTAMP_FMT: &[FormatItem] =
     format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]");
 
@@ -88,190 +764,866 @@ fn main() -> eyre::Result<()> {
         .init();
 
     let cli = Cli::parse();
-    let logfile = cli.logfile.unwrap_or_else(|| {
+    let path = cli.logfile.unwrap_or_else(|| {
         home_dir()
             .ok_or_else(|| eyre!("Failed to find home directory"))
             .unwrap()
             .join(".ttrk.json")
     });
 
-    let mut old_content = String::new();
-    let mut file;
-    if !logfile.is_file() {
-        file = File::options()
-            .write(true)
-            .create_new(true)
-            .open(&logfile)
-            .wrap_err(eyre!(
-                "Failed to create log file at `{}`",
-                logfile.display()
-            ))?;
-        info!("Created log file at `{}`", logfile.display());
-    } else {
-        old_content = fs::read_to_string(&logfile)
-            .wrap_err(eyre!("Failed to read log file at `{}`", logfile.display()))?;
-        file = File::options()
-            .write(true)
-            .open(&logfile)
-            .wrap_err(eyre!("Failed to open log file at `{}`", logfile.display()))?;
-        info!("Using log file at `{}`", logfile.display());
-    };
-
-    let mut log = if old_content.is_empty() {
-        Log::default()
-    } else {
-        serde_json::from_str(&old_content).wrap_err(eyre!("Failed to parse log file"))?
+    let command = match cli.command {
+        AnyCommand::Repair => return repair(&path, cli.backups, cli.storage),
+        AnyCommand::Lookup(command) => {
+            let logfile = LogFile::open_for_queries(&path, cli.backups, cli.storage)?;
+            let config = Config::open(&logfile.sidecar_path(".config.json"))?;
+            let calendar = config.calendar(&cli.calendar)?;
+            return look_up(command, logfile, &calendar, cli.format);
+        }
+        AnyCommand::Log(command) => command,
     };
 
-    let time_on_at_fmt = format_description!("on [month]-[day]-[year] at [hour]:[minute]:[second] (UTC[offset_hour sign:mandatory]:[offset_second])");
+    let (mut logfile, mut log) =
+        LogFile::open(&path, command.is_read_only(), cli.backups, cli.storage)?;
+    log.assign_ids();
+    let mut journal = Journal::open(logfile.sidecar_path(".journal"))?;
+    let before = serde_json::to_value(&log)?;
+    // Undo and redo move through the journal rather than adding to it.
+    let journaled = !matches!(command, Commands::Undo | Commands::Redo);
 
-    match cli.command {
-        Commands::Begin => match log.current {
+    // Whether the command changed the log, so it needs to be written.
+    let changed = match command {
+        Commands::Begin { project, tags, at } => match log.current {
             Some(ref sess) => {
                 error!(
                     "There is already a current session, started {}.",
-                    sess.start.0.format(time_on_at_fmt)?
+                    sess.start.0.format(TIME_ON_AT_FMT)?
                 );
+                false
             }
             None => {
-                log.current = Some(Session {
-                    start: Time(get_time()?),
-                    end: None,
-                    message: None,
-                });
+                log.current = Some(begin_session(&log, get_time_at(at)?, project, tags)?);
                 println!("Started a session.");
+                true
             }
         },
-        Commands::End { message } => match log.current.take() {
-            Some(mut sess) => {
-                sess.end = Some(Time(get_time()?));
-                if message.contains('\n') {
-                    bail!("A message for a completed session must be one line.");
-                }
-                sess.message = Some(message);
+        Commands::End { message, tags, at } => match log.current.take() {
+            Some(sess) => {
+                let sess = end_session(sess, get_time_at(at)?, message, tags)?;
                 println!(
                     "Ended session started at {}.\nElapsed time: {}.",
-                    sess.start.0.format(time_on_at_fmt)?,
-                    display_duration(sess.end.unwrap().0 - sess.start.0)
+                    sess.start.0.format(TIME_ON_AT_FMT)?,
+                    display_duration(sess.elapsed())
                 );
                 log.completed.push(sess);
+                true
             }
             None => {
                 error!("There is no current session.");
+                false
             }
         },
-        Commands::Cancel => match log.current {
-            Some(ref mut sess) => {
-                let time = sess.start;
-                log.current = None;
+        Commands::Switch {
+            message,
+            project,
+            tags,
+            at,
+        } => match log.current.take() {
+            Some(sess) => {
+                let time = get_time_at(at)?;
+                let sess = end_session(sess, time, message, vec![])?;
                 println!(
-                    "Canceled session that was started {}.",
-                    time.0.format(time_on_at_fmt)?
+                    "Ended session started at {}.\nElapsed time: {}.",
+                    sess.start.0.format(TIME_ON_AT_FMT)?,
+                    display_duration(sess.elapsed())
                 );
+                log.completed.push(sess);
+                log.current = Some(begin_session(&log, time, project, tags)?);
+                println!("Started a session.");
+                true
             }
             None => {
                 error!("There is no current session.");
+                false
             }
         },
-        Commands::Status => {
+        Commands::Continue {
+            reopen,
+            project,
+            tags,
+            at,
+        } => {
+            if let Some(ref sess) = log.current {
+                error!(
+                    "There is already a current session, started {}.",
+                    sess.start.0.format(TIME_ON_AT_FMT)?
+                );
+                false
+            } else if log.completed.is_empty() {
+                error!("There are no completed sessions to continue.");
+                false
+            } else if reopen {
+                let time = get_time_at(at)?;
+                let mut sess = log.completed.pop().unwrap();
+                let end = sess.end.take().unwrap();
+                if time < end.0 {
+                    bail!(
+                        "A session cannot be reopened before it ended {}.",
+                        end.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+                // If the session ended while paused, that break just goes on
+                // for longer.
+                match sess.breaks.last_mut() {
+                    Some(br) if br.end.map(|br_end| br_end.0) == Some(end.0) => {
+                        br.end = Some(Time(time));
+                    }
+                    _ if time > end.0 => sess.breaks.push(Break {
+                        start: end,
+                        end: Some(Time(time)),
+                    }),
+                    _ => {}
+                }
+                println!(
+                    "Reopened session started {}.\nElapsed time: {}.",
+                    sess.start.0.format(TIME_ON_AT_FMT)?,
+                    display_duration(sess.elapsed_until(time))
+                );
+                log.current = Some(sess);
+                true
+            } else {
+                let last = log.completed.last().unwrap();
+                let project = project.or_else(|| last.project.clone());
+                let tags = if tags.is_empty() {
+                    last.tags.iter().cloned().collect()
+                } else {
+                    tags
+                };
+                let mut sess = begin_session(&log, get_time_at(at)?, project, tags)?;
+                sess.message = last.message.clone();
+                log.current = Some(sess);
+                println!("Started a session.");
+                true
+            }
+        }
+        Commands::Add {
+            message,
+            from,
+            to,
+            project,
+            tags,
+        } => {
+            let now = get_time()?;
+            let sess = begin_session(&log, from.resolve(now)?, project, tags)?;
+            let sess = end_session(sess, to.resolve(now)?, Some(message), vec![])?;
+            let (start, end) = (sess.start.0, sess.end.unwrap().0);
+            for other in &log.completed {
+                if other.start.0 < end && start < other.end.unwrap().0 {
+                    warn!(
+                        "This session overlaps with the session from {} to {}: \"{}\"",
+                        other.start.0.format(TIMESTAMP_FMT)?,
+                        other.end.unwrap().0.format(TIMESTAMP_FMT)?,
+                        other.message.as_ref().unwrap()
+                    );
+                }
+            }
+            if let Some(ref current) = log.current {
+                if current.start.0 < end {
+                    warn!(
+                        "This session overlaps with the current session, started {}.",
+                        current.start.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+            }
             println!(
-                "=== Status ===\n- Logged {} completed session{}.",
-                log.completed.len(),
-                if log.completed.len() != 1 { "s" } else { "" }
+                "Added session {} started {}.\nElapsed time: {}.",
+                sess.id,
+                start.format(TIME_ON_AT_FMT)?,
+                display_duration(sess.elapsed())
             );
-            let mut elapsed_total = Duration::default();
-            let today = get_time()?.date();
-            let thisweek = get_time()?.sunday_based_week();
-            let mut elapsed_today = Duration::default();
-            let mut elapsed_thisweek = Duration::default();
-            for session in &log.completed {
-                let end = session.end.unwrap().0;
-                let start = session.start.0;
-                elapsed_total += end - start;
-                if start.date() == today && end.date() == today {
-                    elapsed_today += end - start;
-                }
-                if start.sunday_based_week() == thisweek && end.sunday_based_week() == thisweek {
-                    elapsed_thisweek += end - start;
+            let index = log
+                .completed
+                .partition_point(|other| other.start.0 <= start);
+            log.completed.insert(index, sess);
+            true
+        }
+        Commands::Edit {
+            id,
+            start,
+            end,
+            message,
+        } => {
+            let index = log.find_completed(&id)?;
+            let mut sess = log.completed[index].clone();
+            let now = get_time()?;
+            if let Some(start) = start {
+                sess.start = Time(start.resolve(now)?);
+            }
+            if let Some(end) = end {
+                sess.end = Some(Time(end.resolve(now)?));
+            }
+            if let Some(message) = message {
+                if message.contains('\n') {
+                    bail!("A message for a completed session must be one line.");
                 }
+                sess.message = Some(message);
+            }
+            if sess.end.unwrap().0 < sess.start.0 {
+                bail!("A session cannot end before it began.");
             }
+            validate_breaks(&sess)?;
             println!(
-                "- Total elapsed time (completed only): {}\n- Total elapsed time today (completed only): {}\n- Total elapsed time this week (completed only): {}",
-                display_duration(elapsed_total),
-                display_duration(elapsed_today),
-                display_duration(elapsed_thisweek),
+                "Edited session {}.\nElapsed time: {}.",
+                sess.id,
+                display_duration(sess.elapsed())
             );
-            if !log.completed.is_empty() {
-                let last = log.completed.iter().last().unwrap();
-                let start = last.start.0;
-                let end = last.end.unwrap().0;
+            log.completed.remove(index);
+            let index = log
+                .completed
+                .partition_point(|other| other.start.0 <= sess.start.0);
+            log.completed.insert(index, sess);
+            true
+        }
+        Commands::Amend { message, edit } => match log.completed.last_mut() {
+            Some(sess) => {
+                let message = match message {
+                    Some(message) => message,
+                    // Clap makes sure `--edit` was given.
+                    None => {
+                        // As with `fixup`, don't keep other ttrk processes
+                        // waiting while the editor is open.
+                        logfile.unlock()?;
+                        let s = edit_in_editor(&format!(
+                            "{}\n# Enter the new message for session {}. It must be one line.\n#\n# Lines starting with `#` are ignored, and an empty message aborts.\n",
+                            sess.message.as_ref().unwrap(),
+                            sess.id
+                        ))?;
+                        let message = s
+                            .lines()
+                            .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+                            .collect::<Vec<_>>();
+                        match message[..] {
+                            [] => bail!("Aborting due to an empty message."),
+                            [message] => message.trim().to_string(),
+                            _ => bail!("A message for a completed session must be one line."),
+                        }
+                    }
+                };
+                if edit {
+                    logfile.relock().wrap_err(eyre!(
+                        "Refusing to amend session {}. Your message was: \"{}\"",
+                        sess.id,
+                        message
+                    ))?;
+                }
+                if message.contains('\n') {
+                    bail!("A message for a completed session must be one line.");
+                }
                 println!(
-                    "\n=== Most recent completed session ===\n- Began {}\n- Ended {}\n- Time elapsed: {}\n- Message: \"{}\"",
-                    start.format(time_on_at_fmt)?,
-                    end.format(time_on_at_fmt)?,
-                    display_duration(end - start),
-                    last.message.as_ref().unwrap()
+                    "Amended session {}.\nOld message: \"{}\"",
+                    sess.id,
+                    sess.message.as_ref().unwrap()
                 );
+                sess.message = Some(message);
+                true
             }
-            if let Some(ref sess) = log.current {
-                println!(
-                    "\n=== Current session ===\n- Began {}\n- Time elapsed: {}",
-                    sess.start.0.format(time_on_at_fmt)?,
-                    display_duration(get_time()? - sess.start.0)
+            None => {
+                error!("There are no completed sessions.");
+                false
+            }
+        },
+        Commands::Split { id, at, message } => {
+            let index = log.find_completed(&id)?;
+            let at = at.resolve(get_time()?)?;
+            let first = &log.completed[index];
+            if at <= first.start.0 || at >= first.end.unwrap().0 {
+                bail!(
+                    "A session can only be split between when it began {} and ended {}.",
+                    first.start.0.format(TIME_ON_AT_FMT)?,
+                    first.end.unwrap().0.format(TIME_ON_AT_FMT)?
                 );
             }
+            if message
+                .as_ref()
+                .is_some_and(|message| message.contains('\n'))
+            {
+                bail!("A message for a completed session must be one line.");
+            }
+            let mut second = first.clone();
+            second.id = log.new_id();
+            second.start = Time(at);
+            if message.is_some() {
+                second.message = message;
+            }
+            let first = &mut log.completed[index];
+            first.end = Some(Time(at));
+            // Breaks that go over the split are split too.
+            first.breaks.retain(|br| br.start.0 < at);
+            if let Some(br) = first.breaks.last_mut() {
+                if br.end.unwrap().0 > at {
+                    br.end = Some(Time(at));
+                }
+            }
+            second.breaks.retain(|br| br.end.unwrap().0 > at);
+            if let Some(br) = second.breaks.first_mut() {
+                if br.start.0 < at {
+                    br.start = Time(at);
+                }
+            }
+            println!(
+                "Split session {} into {} ({}) and {} ({}).",
+                first.id,
+                first.id,
+                display_duration(first.elapsed()),
+                second.id,
+                display_duration(second.elapsed())
+            );
+            log.completed.insert(index + 1, second);
+            true
         }
-        Commands::List => {
-            println!("{}", format_log(&log)?);
+        Commands::Merge {
+            first,
+            second,
+            keep,
+            message,
+        } => {
+            let (first, second) = match (log.find_completed(&first)?, log.find_completed(&second)?)
+            {
+                (first, second) if first + 1 == second => (first, second),
+                (first, second) if second + 1 == first => (second, first),
+                _ => bail!("Only neighbouring sessions can be merged."),
+            };
+            let later = log.completed[second].clone();
+            let sess = &mut log.completed[first];
+            if sess.project.is_some() && later.project.is_some() && sess.project != later.project {
+                bail!("Sessions in different projects cannot be merged.");
+            }
+            if message
+                .as_ref()
+                .is_some_and(|message| message.contains('\n'))
+            {
+                bail!("A message for a completed session must be one line.");
+            }
+            let mut merged = sess.clone();
+            let end = merged.end.unwrap();
+            if later.start.0 > end.0 {
+                merged.breaks.push(Break {
+                    start: end,
+                    end: Some(later.start),
+                });
+            }
+            merged.breaks.extend(later.breaks);
+            merged.end = Some(Time(end.0.max(later.end.unwrap().0)));
+            merged.project = merged.project.or(later.project);
+            merged.tags.extend(later.tags);
+            for (key, value) in later.extra {
+                merged.extra.entry(key).or_insert(value);
+            }
+            merged.message = match (message, keep) {
+                (Some(message), _) => Some(message),
+                (None, KeepMessage::First) => merged.message,
+                (None, KeepMessage::Second) => later.message,
+                (None, KeepMessage::Both) => Some(format!(
+                    "{}; {}",
+                    merged.message.unwrap(),
+                    later.message.unwrap()
+                )),
+            };
+            validate_breaks(&merged)?;
+            println!(
+                "Merged sessions {} and {} into {}.\nElapsed time: {}.",
+                merged.id,
+                later.id,
+                merged.id,
+                display_duration(merged.elapsed())
+            );
+            *sess = merged;
+            log.completed.remove(second);
+            true
         }
+        Commands::Delete { id } => {
+            let index = log.find_completed(&id)?;
+            let sess = log.completed.remove(index);
+            println!(
+                "Deleted session {} started {}: \"{}\"",
+                sess.id,
+                sess.start.0.format(TIME_ON_AT_FMT)?,
+                sess.message.unwrap()
+            );
+            true
+        }
+        Commands::Cancel { at } => match log.current {
+            Some(ref mut sess) => {
+                let time = sess.start;
+                let canceled = get_time_at(at)?;
+                let discarded = sess.elapsed_until(canceled);
+                if canceled < time.0 {
+                    bail!(
+                        "A session cannot be canceled before it began {}.",
+                        time.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+                log.current = None;
+                println!(
+                    "Canceled session that was started {}.\nDiscarded time: {}.",
+                    time.0.format(TIME_ON_AT_FMT)?,
+                    display_duration(discarded)
+                );
+                true
+            }
+            None => {
+                error!("There is no current session.");
+                false
+            }
+        },
+        Commands::Pause { at } => match log.current {
+            Some(ref mut sess) => {
+                if let Some(br) = sess.paused() {
+                    bail!(
+                        "The current session is already paused, since {}.",
+                        br.start.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+                let start = get_time_at(at)?;
+                let earliest = sess.breaks.last().map_or(sess.start, |br| br.end.unwrap());
+                if start < earliest.0 {
+                    bail!(
+                        "A session cannot be paused before {}.",
+                        earliest.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+                sess.breaks.push(Break {
+                    start: Time(start),
+                    end: None,
+                });
+                println!(
+                    "Paused the current session.\nElapsed time: {}.",
+                    display_duration(sess.elapsed_until(start))
+                );
+                true
+            }
+            None => {
+                error!("There is no current session.");
+                false
+            }
+        },
+        Commands::Resume { at } => match log.current {
+            Some(ref mut sess) => {
+                let end = get_time_at(at)?;
+                let br = match sess.breaks.last_mut().filter(|br| br.end.is_none()) {
+                    Some(br) => br,
+                    None => bail!("The current session is not paused."),
+                };
+                if end < br.start.0 {
+                    bail!(
+                        "A session cannot be resumed before it was paused {}.",
+                        br.start.0.format(TIME_ON_AT_FMT)?
+                    );
+                }
+                br.end = Some(Time(end));
+                println!(
+                    "Resumed the current session.\nBreak time: {}.",
+                    display_duration(end - br.start.0)
+                );
+                true
+            }
+            None => {
+                error!("There is no current session.");
+                false
+            }
+        },
         Commands::Fixup => {
-            // This one is super hacky, but it works.
-            let editor = env::var("EDITOR")
-                .wrap_err(eyre!("Failed to get `$EDITOR` environment variable."))?;
-            let tmpdir = tempdir()?;
-            let mut tmpfile_path = tmpdir.path().to_owned();
-            let mut tmpfile = NamedTempFile::new_in(&tmpdir)
-                .wrap_err(eyre!("Failed to create a temporary file."))?;
-            tmpfile_path.push(tmpfile.path());
+            let mut s = String::new();
             writeln!(
-                tmpfile,
+                s,
                 r#"# Here you can fix up any entries of the log. One log entry per line.
 #
 # Empty lines or lines starting with `#` are ignored.
 #
+# Each entry starts with its ID. Leave the IDs untouched; new entries can be
+# written without one and will be given one.
+#
 # Duration is ignored--don't worry about calculating it, just leave the
 # duration within the parens untouched, or remove it (but keeping the parens).
 #
-# Current log entries, marked with an end time of `[now]`, cannot have a
-# message.
+# Current log entries, marked with an end time of `[now]`, may have a draft
+# message, which `end` uses if it is not given one.
+#
+# An entry's project is written as `@project` after the duration, followed
+# by its tags, each written as `+tag`. Remove them to take the entry out of
+# its project or to untag it.
+#
+# Breaks are written on their own indented lines, starting with `break`,
+# right after the entry they belong to. Only the last break of a current
+# entry can have an end time of `[now]`.
 #
 # For current entries, do not worry about messing up the padding--
 # it is ignored.
 #
 # Example format:
-# 06-24-2022 16:55:46 (UTC-05:00) -> 06-24-2022 16:55:49 (UTC-05:00) (3 seconds): Message here
+# 3fa2c1 06-24-2022 16:55:46 (UTC-05:00) -> 06-24-2022 16:55:49 (UTC-05:00) (3 seconds): Message here
+#
+# With a project and tags:
+# 3fa2c1 06-24-2022 16:55:46 (UTC-05:00) -> 06-24-2022 16:55:49 (UTC-05:00) (3 seconds) @project +tag +other: Message here
+#
+# With a break:
+# 3fa2c1 06-24-2022 11:02:13 (UTC-05:00) -> 06-24-2022 13:30:00 (UTC-05:00) (1 hour, 57 minutes, 47 seconds): Message here
+#     break 06-24-2022 12:00:00 (UTC-05:00) -> 06-24-2022 12:30:00 (UTC-05:00) (30 minutes, 0 seconds)
 #
 # For current entries:
-# 06-24-2022 17:21:10 (UTC-05:00) -> [now]                           (35 minutes, 47 seconds)
+# 3fa2c1 06-24-2022 17:21:10 (UTC-05:00) -> [now]                           (35 minutes, 47 seconds)
 "#
             )?;
-            write!(tmpfile, "{}", format_log(&log)?)?;
-            tmpfile.flush()?;
-
-            let path = tmpfile.into_temp_path();
-            Command::new(editor)
-                .arg(&tmpfile_path)
-                .spawn()
-                .wrap_err(eyre!("Failed to open an editor."))?
-                .wait()
-                .wrap_err(eyre!("Failed to open an editor."))?;
-            let s = fs::read_to_string(&tmpfile_path)?;
-            path.close()?;
-            tmpdir.close()?;
-            log = parse_log_fmtd(s).wrap_err(eyre!("Failed to parse new log."))?;
+            write!(s, "{}", format_log(&log.sessions().collect::<Vec<_>>())?)?;
+            // Don't keep other ttrk processes waiting while the editor is
+            // open.
+            logfile.unlock()?;
+            let s = edit_in_editor(&s)?;
+            if let Err(e) = logfile.relock() {
+                let (mut file, path) = tempfile::Builder::new()
+                    .prefix("ttrk-fixup-")
+                    .suffix(".txt")
+                    .tempfile()?
+                    .keep()?;
+                file.write_all(s.as_bytes())?;
+                return Err(e.wrap_err(eyre!(
+                    "Refusing to overwrite the log. Your edits were saved to `{}`.",
+                    path.display()
+                )));
+            }
+            let mut fixed = parse_log_fmtd(s).wrap_err(eyre!("Failed to parse new log."))?;
+            fixed.keep_extra_from(&log);
+            log = fixed;
             println!("Successfully edited the log.");
+            true
+        }
+        Commands::Compact => {
+            if logfile.stored_as() == Some(Storage::Events) || cli.storage.is_some() {
+                logfile.rewrite(&log)?;
+                println!("Compacted the log file.");
+                // Nothing is left to write, but it goes in the journal.
+                true
+            } else {
+                error!("The log file is not stored as events, so there is nothing to compact.");
+                false
+            }
+        }
+        Commands::Migrate { to } => {
+            if logfile.stored_as() == Some(to) {
+                error!("The log file is already stored that way.");
+            } else {
+                logfile.convert(to, &log)?;
+                println!("Converted the log file.");
+            }
+            false
+        }
+        Commands::Backups {
+            command: BackupsCommand::List,
+        } => {
+            let backups = logfile.backups()?;
+            if backups.is_empty() {
+                println!("There are no backups.");
+            }
+            for backup in backups {
+                let modified = OffsetDateTime::from(backup.modified)
+                    .to_offset(get_time()?.offset())
+                    .replace_nanosecond(0)?;
+                print!("{}: {}", backup.number, modified.format(TIMESTAMP_FMT)?);
+                match backup.log {
+                    Ok(log) => println!(
+                        ", {} completed session{}{}",
+                        log.completed.len(),
+                        if log.completed.len() != 1 { "s" } else { "" },
+                        if log.current.is_some() {
+                            " and a current session"
+                        } else {
+                            ""
+                        }
+                    ),
+                    Err(e) => println!(", could not be read: {}", e),
+                }
+            }
+            false
+        }
+        Commands::Backups {
+            command: BackupsCommand::Restore { number },
+        } => {
+            log = logfile.read_backup(number)?;
+            log.assign_ids();
+            println!("Restored backup {}.", number);
+            true
+        }
+        Commands::Undo => match journal.undo(&before)? {
+            Some((entry, undone)) => {
+                println!(
+                    "Undid `{}`, made {}.",
+                    entry.command,
+                    entry.time.0.format(TIME_ON_AT_FMT)?
+                );
+                log = serde_json::from_value(undone)?;
+                true
+            }
+            None => {
+                error!("There is nothing to undo.");
+                false
+            }
+        },
+        Commands::Redo => match journal.redo(&before)? {
+            Some((entry, redone)) => {
+                println!(
+                    "Redid `{}`, made {}.",
+                    entry.command,
+                    entry.time.0.format(TIME_ON_AT_FMT)?
+                );
+                log = serde_json::from_value(redone)?;
+                true
+            }
+            None => {
+                error!("There is nothing to redo.");
+                false
+            }
+        },
+        Commands::History => {
+            let mut entries = journal.entries().peekable();
+            if entries.peek().is_none() {
+                println!("There are no changes to show.");
+            }
+            for (entry, undone) in entries {
+                println!(
+                    "{}: {}{}",
+                    entry.time.0.format(TIMESTAMP_FMT)?,
+                    entry.command,
+                    if undone { " (undone)" } else { "" }
+                );
+            }
+            false
         }
-        Commands::Csv => {
+    };
+
+    if changed {
+        // The journal goes first, so that if ttrk is stopped before the log is
+        // written, the journal can tell the change never made it.
+        if journaled {
+            let args = env::args().skip(1).collect::<Vec<_>>();
+            let command = format!("ttrk {}", args.join(" "));
+            journal.record(
+                Time(get_time()?),
+                command,
+                before,
+                serde_json::to_value(&log)?,
+            );
+        }
+        journal.save()?;
+        logfile.write(&log)?;
+    }
+    Ok(())
+}
+
+/// Run a command that only looks sessions up.
+fn look_up(
+    command: Lookup,
+    mut logfile: LogFile,
+    calendar: &Calendar,
+    format: Format,
+) -> eyre::Result<()> {
+    match command {
+        Lookup::Show { id } => {
+            let sessions = logfile.query(&Query {
+                id: Some(id.clone()),
+                ..Query::default()
+            })?;
+            let (_, sess) = find_session(&sessions, &id)?;
+            println!(
+                "=== Session {} ===\n- Began {}",
+                sess.id,
+                sess.start.0.format(TIME_ON_AT_FMT)?
+            );
+            match sess.end {
+                Some(end) => println!(
+                    "- Ended {}\n- Time elapsed: {}",
+                    end.0.format(TIME_ON_AT_FMT)?,
+                    display_duration(sess.elapsed())
+                ),
+                None => println!(
+                    "- Current session\n- Time elapsed: {}",
+                    display_duration(sess.elapsed_until(get_time()?))
+                ),
+            }
+            if let Some(ref project) = sess.project {
+                println!("- Project: {}", project);
+            }
+            if !sess.tags.is_empty() {
+                println!("- Tags: {}", join_tags(&sess.tags));
+            }
+            if let Some(ref message) = sess.message {
+                println!("- Message: \"{}\"", message);
+            }
+            for br in &sess.breaks {
+                match br.end {
+                    Some(end) => println!(
+                        "- Break {} to {}",
+                        br.start.0.format(TIMESTAMP_FMT)?,
+                        end.0.format(TIMESTAMP_FMT)?
+                    ),
+                    None => println!("- Paused {}", br.start.0.format(TIME_ON_AT_FMT)?),
+                }
+            }
+        }
+        Lookup::Status { filter } => {
+            let selected = filter.look_up(&mut logfile, calendar, false)?;
+            let (completed, current) = selected
+                .iter()
+                .partition::<Vec<_>, _>(|session| session.end.is_some());
+            let mut elapsed_total = Duration::default();
+            let now = get_time()?;
+            // As with `report`, only time spent within the filter's range
+            // counts.
+            let range = filter.range(now, calendar)?;
+            let today = Period::day(now, calendar).within(range);
+            let thisweek = Period::week(now, calendar).within(range);
+            let mut elapsed_today = Duration::default();
+            let mut elapsed_thisweek = Duration::default();
+            let mut elapsed_projects = BTreeMap::<Option<&str>, Duration>::new();
+            let mut elapsed_tags = BTreeMap::<Option<&str>, Duration>::new();
+            for session in &completed {
+                let whole = Period {
+                    start: session.start.0,
+                    end: session.end.unwrap().0,
+                };
+                let elapsed = session.elapsed_in(whole.within(range));
+                elapsed_total += elapsed;
+                elapsed_today += session.elapsed_in(today);
+                elapsed_thisweek += session.elapsed_in(thisweek);
+                *elapsed_projects
+                    .entry(session.project.as_deref())
+                    .or_default() += elapsed;
+                if session.tags.is_empty() {
+                    *elapsed_tags.entry(None).or_default() += elapsed;
+                }
+                for tag in &session.tags {
+                    *elapsed_tags.entry(Some(tag)).or_default() += elapsed;
+                }
+            }
+            if format == Format::Json {
+                output::print(&output::Status {
+                    completed: completed.len(),
+                    elapsed: output::Elapsed {
+                        total: elapsed_total.whole_seconds(),
+                        today: elapsed_today.whole_seconds(),
+                        week: elapsed_thisweek.whole_seconds(),
+                    },
+                    projects: elapsed_projects
+                        .iter()
+                        .map(|(project, elapsed)| output::ProjectTotal {
+                            project: *project,
+                            elapsed: elapsed.whole_seconds(),
+                        })
+                        .collect(),
+                    tags: elapsed_tags
+                        .iter()
+                        .map(|(tag, elapsed)| output::TagTotal {
+                            tag: *tag,
+                            elapsed: elapsed.whole_seconds(),
+                        })
+                        .collect(),
+                    last: completed
+                        .last()
+                        .map(|session| output::SessionJson::new(session, now)),
+                    current: current
+                        .first()
+                        .map(|session| output::SessionJson::new(session, now)),
+                })?;
+            } else {
+                println!(
+                    "=== Status ===\n- Logged {} completed session{}.",
+                    completed.len(),
+                    if completed.len() != 1 { "s" } else { "" }
+                );
+                println!(
+                    "- Total elapsed time (completed only): {}\n- Total elapsed time today (completed only): {}\n- Total elapsed time this week (completed only): {}",
+                    display_duration(elapsed_total),
+                    display_duration(elapsed_today),
+                    display_duration(elapsed_thisweek),
+                );
+                if elapsed_projects.keys().any(Option::is_some) {
+                    println!("\n=== Projects (completed only) ===");
+                    for (project, elapsed) in &elapsed_projects {
+                        println!(
+                            "- {}: {}",
+                            project.unwrap_or("(no project)"),
+                            display_duration(*elapsed)
+                        );
+                    }
+                }
+                if elapsed_tags.keys().any(Option::is_some) {
+                    println!("\n=== Tags (completed only) ===");
+                    for (tag, elapsed) in &elapsed_tags {
+                        println!(
+                            "- {}: {}",
+                            tag.unwrap_or("(untagged)"),
+                            display_duration(*elapsed)
+                        );
+                    }
+                }
+                if let Some(last) = completed.last() {
+                    let start = last.start.0;
+                    let end = last.end.unwrap().0;
+                    println!(
+                        "\n=== Most recent completed session ===\n- ID: {}\n- Began {}\n- Ended {}\n- Time elapsed: {}\n- Message: \"{}\"",
+                        last.id,
+                        start.format(TIME_ON_AT_FMT)?,
+                        end.format(TIME_ON_AT_FMT)?,
+                        display_duration(last.elapsed()),
+                        last.message.as_ref().unwrap()
+                    );
+                }
+                if let Some(sess) = current.first() {
+                    println!(
+                        "\n=== Current session ===\n- Began {}\n- Time elapsed: {}",
+                        sess.start.0.format(TIME_ON_AT_FMT)?,
+                        display_duration(sess.elapsed_until(get_time()?))
+                    );
+                    if let Some(br) = sess.paused() {
+                        println!("- Paused {}", br.start.0.format(TIME_ON_AT_FMT)?);
+                    }
+                    if let Some(ref project) = sess.project {
+                        println!("- Project: {}", project);
+                    }
+                    if !sess.tags.is_empty() {
+                        println!("- Tags: {}", join_tags(&sess.tags));
+                    }
+                    if let Some(ref message) = sess.message {
+                        println!("- Draft message: \"{}\"", message);
+                    }
+                }
+            }
+        }
+        Lookup::List { filter } => {
+            let sessions = filter.look_up(&mut logfile, calendar, false)?;
+            if format == Format::Json {
+                let now = get_time()?;
+                output::print(&output::List {
+                    sessions: sessions
+                        .iter()
+                        .map(|session| output::SessionJson::new(session, now))
+                        .collect(),
+                })?;
+            } else {
+                println!("{}", format_log(&sessions.iter().collect::<Vec<_>>())?);
+            }
+        }
+        Lookup::Report { by, filter } => {
+            let now = get_time()?;
+            let sessions = filter.look_up(&mut logfile, calendar, true)?;
+            let sessions = sessions.iter().collect::<Vec<_>>();
+            let range = filter.range(now, calendar)?;
+            let (rows, total) = report::report(&sessions, by, calendar, range, now.offset())?;
+            if format == Format::Json {
+                output::print(&output::Report::new(by, &rows, &total))?;
+            } else {
+                print!("{}", report::format_report(&rows, &total)?);
+            }
+        }
+        Lookup::Csv { filter } => {
             let mut csv = csv::Writer::from_writer(io::stdout());
             csv.serialize((
                 "UTC-Start",
@@ -280,11 +1632,14 @@ fn main() -> eyre::Result<()> {
                 "Minutes",
                 "Seconds",
                 "Message",
+                "Project",
+                "Tags",
+                "ID",
             ))?;
-            for session in &log.completed {
+            for session in filter.look_up(&mut logfile, calendar, true)? {
                 let start = session.start.0;
                 let end = session.end.unwrap().0;
-                let seconds = (end - start).whole_seconds();
+                let seconds = session.elapsed().whole_seconds();
                 let minutes = seconds / 60;
                 let hours = seconds / (60 * 60);
                 csv.serialize((
@@ -297,58 +1652,313 @@ fn main() -> eyre::Result<()> {
                     minutes % 60,
                     seconds % 60,
                     session.message.as_ref().unwrap(),
+                    session.project.as_deref().unwrap_or(""),
+                    join_tags(&session.tags),
+                    &session.id,
                 ))?;
             }
             csv.flush()?;
         }
     }
+    Ok(())
+}
 
-    file.set_len(0)?;
-    serde_json::to_writer(&mut file, &log).wrap_err(eyre!("Failed to write to log file"))?;
+/// Salvage what can be salvaged from a broken log file, and replace it once the
+/// user agrees to it.
+fn repair(path: &Path, backups: usize, storage: Option<Storage>) -> eyre::Result<()> {
+    let mut logfile = LogFile::open_broken(path, backups, storage)?;
+    let Some(salvaged) = logfile.salvage()? else {
+        println!("The log file is not broken, so there is nothing to repair.");
+        return Ok(());
+    };
+    let mut log = salvaged.log;
+    log.assign_ids();
+    println!(
+        "Recovered {} completed session{}{}.",
+        log.completed.len(),
+        if log.completed.len() != 1 { "s" } else { "" },
+        if log.current.is_some() {
+            " and a current session"
+        } else {
+            ""
+        }
+    );
+    if !salvaged.problems.is_empty() {
+        println!("\nCould not recover everything:");
+        for problem in &salvaged.problems {
+            println!("- {}", problem);
+        }
+    }
+    print!("\nReplace the log file with what was recovered? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        println!("Left the log file as it was.");
+        return Ok(());
+    }
+    logfile.rewrite(&log)?;
+    if backups > 0 {
+        println!(
+            "Repaired the log file. The broken one was backed up to `{}`.",
+            logfile.sidecar_path(".bak.1").display()
+        );
+    } else {
+        println!("Repaired the log file.");
+    }
     Ok(())
 }
 
-fn format_log(log: &Log) -> eyre::Result<String> {
+/// Let the user edit `s` in their `$EDITOR`, returning the edited text.
+fn edit_in_editor(s: &str) -> eyre::Result<String> {
+    // This one is super hacky, but it works.
+    let editor =
+        env::var("EDITOR").wrap_err(eyre!("Failed to get `$EDITOR` environment variable."))?;
+    let tmpdir = tempdir()?;
+    let mut tmpfile_path = tmpdir.path().to_owned();
+    let mut tmpfile =
+        NamedTempFile::new_in(&tmpdir).wrap_err(eyre!("Failed to create a temporary file."))?;
+    tmpfile_path.push(tmpfile.path());
+    write!(tmpfile, "{}", s)?;
+    tmpfile.flush()?;
+
+    let path = tmpfile.into_temp_path();
+    Command::new(editor)
+        .arg(&tmpfile_path)
+        .spawn()
+        .wrap_err(eyre!("Failed to open an editor."))?
+        .wait()
+        .wrap_err(eyre!("Failed to open an editor."))?;
+    let s = fs::read_to_string(&tmpfile_path)?;
+    path.close()?;
+    tmpdir.close()?;
+    Ok(s)
+}
+
+/// Make a new current session starting at `start`.
+fn begin_session(
+    log: &Log,
+    start: OffsetDateTime,
+    project: Option<String>,
+    tags: Vec<String>,
+) -> eyre::Result<Session> {
+    if let Some(ref project) = project {
+        validate_name("project", project)?;
+    }
+    for tag in &tags {
+        validate_name("tag", tag)?;
+    }
+    Ok(Session {
+        id: log.new_id(),
+        start: Time(start),
+        end: None,
+        message: None,
+        project,
+        tags: tags.into_iter().collect(),
+        breaks: vec![],
+        extra: Map::new(),
+    })
+}
+
+/// Complete `sess` at `end`, ending its break if it is paused. If there is
+/// no `message`, the session's draft message is used.
+fn end_session(
+    mut sess: Session,
+    end: OffsetDateTime,
+    message: Option<String>,
+    tags: Vec<String>,
+) -> eyre::Result<Session> {
+    let message = match message.or_else(|| sess.message.take()) {
+        Some(message) => message,
+        None => bail!("A message is required, as the session has no draft message."),
+    };
+    if end < sess.start.0 {
+        bail!(
+            "A session cannot end before it began {}.",
+            sess.start.0.format(TIME_ON_AT_FMT)?
+        );
+    }
+    match sess.breaks.last_mut() {
+        Some(br) if br.end.is_none() => {
+            if end < br.start.0 {
+                bail!(
+                    "A session cannot end before it was paused {}.",
+                    br.start.0.format(TIME_ON_AT_FMT)?
+                );
+            }
+            br.end = Some(Time(end));
+        }
+        Some(br) if br.end.is_some_and(|br_end| end < br_end.0) => bail!(
+            "A session cannot end before it was resumed {}.",
+            br.end.unwrap().0.format(TIME_ON_AT_FMT)?
+        ),
+        _ => {}
+    }
+    sess.end = Some(Time(end));
+    if message.contains('\n') {
+        bail!("A message for a completed session must be one line.");
+    }
+    for tag in &tags {
+        validate_name("tag", tag)?;
+    }
+    sess.message = Some(message);
+    sess.tags.extend(tags);
+    Ok(sess)
+}
+
+fn format_log(sessions: &[&Session]) -> eyre::Result<String> {
     let mut s = String::new();
-    for session in &log.completed {
+    for session in sessions {
         let start = session.start.0;
-        let end = session.end.unwrap().0;
+        let Some(end) = session.end else {
+            writeln!(
+                s,
+                "{} {} -> [now]                           ({}){}{}",
+                session.id,
+                start.format(TIMESTAMP_FMT)?,
+                display_duration(session.elapsed_until(get_time()?)),
+                format_labels(session),
+                match session.message {
+                    Some(ref message) => format!(": {}", message),
+                    None => String::new(),
+                }
+            )?;
+            format_breaks(&mut s, session)?;
+            continue;
+        };
+        let end = end.0;
         writeln!(
             s,
-            "{} -> {} ({}): {}",
+            "{} {} -> {} ({}){}: {}",
+            session.id,
             start.format(TIMESTAMP_FMT)?,
             end.format(TIMESTAMP_FMT)?,
-            display_duration(end - start),
+            display_duration(session.elapsed()),
+            format_labels(session),
             session.message.as_ref().unwrap()
         )?;
-    }
-    if let Some(ref session) = log.current {
-        let start = session.start.0;
-        writeln!(
-            s,
-            "{} -> [now]                           ({})",
-            start.format(TIMESTAMP_FMT)?,
-            display_duration(get_time()? - start)
-        )?;
+        format_breaks(&mut s, session)?;
     }
     Ok(s)
 }
 
+/// Write the breaks of a session the way [`BREAK_LINE_REGEX`] expects them.
+fn format_breaks(s: &mut String, session: &Session) -> eyre::Result<()> {
+    for br in &session.breaks {
+        let start = br.start.0;
+        match br.end {
+            Some(end) => writeln!(
+                s,
+                "    break {} -> {} ({})",
+                start.format(TIMESTAMP_FMT)?,
+                end.0.format(TIMESTAMP_FMT)?,
+                display_duration(end.0 - start)
+            )?,
+            None => writeln!(
+                s,
+                "    break {} -> [now]                           ({})",
+                start.format(TIMESTAMP_FMT)?,
+                display_duration(get_time()? - start)
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// Make sure the breaks of a session are in order, do not overlap, and lie
+/// within the session.
+fn validate_breaks(session: &Session) -> eyre::Result<()> {
+    let mut earliest = session.start.0;
+    for (i, br) in session.breaks.iter().enumerate() {
+        if br.start.0 < earliest {
+            bail!("Breaks must be in order, must not overlap, and must be within their session.");
+        }
+        match br.end {
+            Some(end) if end.0 < br.start.0 => bail!("A break cannot end before it began."),
+            Some(end) => earliest = end.0,
+            None if session.end.is_some() || i != session.breaks.len() - 1 => {
+                bail!("Only the last break of a current session can be ongoing.")
+            }
+            None => {}
+        }
+    }
+    if session.end.is_some_and(|end| end.0 < earliest) {
+        bail!("Breaks must be within their session.");
+    }
+    Ok(())
+}
+
+/// Format the project and tags of a session the way [`LOG_LINE_REGEX`]
+/// expects them, including the leading space.
+fn format_labels(session: &Session) -> String {
+    let mut s = String::new();
+    if let Some(ref project) = session.project {
+        write!(s, " @{}", project).unwrap();
+    }
+    for tag in &session.tags {
+        write!(s, " +{}", tag).unwrap();
+    }
+    s
+}
+
+fn join_tags(tags: &BTreeSet<String>) -> String {
+    tags.iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Make sure a project or tag name can round-trip through the fixup format.
+fn validate_name(kind: &str, name: &str) -> eyre::Result<()> {
+    if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == ':') {
+        bail!(
+            "A {} name must be non-empty and must not contain whitespace or `:`.",
+            kind
+        );
+    }
+    Ok(())
+}
+
 /// A very chonky regex that parses the log lines.
 static LOG_LINE_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"(?P<start>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\)) -> (?:(?P<end_time>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\))|(?P<end_current>\[now\](\s+))) \([0-9a-z, ]*\)(?:: (?P<message>.*))?"#).unwrap()
+    Regex::new(r#"(?:(?P<id>[0-9a-f]+) )?(?P<start>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\)) -> (?:(?P<end_time>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\))|(?P<end_current>\[now\](\s+))) \([0-9a-zA-Z/, ]*\)(?: @(?P<project>[^\s:]+))?(?P<tags>(?: \+[^\s:]+)*)(?:: (?P<message>.*))?"#).unwrap()
+});
+
+/// Parses the indented break lines that follow a log line.
+static BREAK_LINE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s+break (?P<start>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\)) -> (?:(?P<end_time>[0-9]{2}-[0-9]{2}-[0-9]{4} [0-9]{2}:[0-9]{2}:[0-9]{2} \(UTC[-+][0-9]{2}:[0-9]{2}\))|(?P<end_current>\[now\]))(?:\s+\([0-9a-zA-Z/, ]*\))?\s*$"#).unwrap()
 });
 
 /// A dirt-simple formatted (with format_log) log parser.
 fn parse_log_fmtd(fmtd: String) -> eyre::Result<Log> {
-    let mut log = Log {
-        completed: vec![],
-        current: None,
-    };
+    let mut log = Log::default();
+    // Whether the last log line was the current one, so we know where breaks
+    // go.
+    let mut last_current = false;
     for line in fmtd.lines() {
         if line.starts_with('#') || line.is_empty() {
             continue;
         }
+        if let Some(caps) = BREAK_LINE_REGEX.captures(line) {
+            let session = if last_current {
+                log.current.as_mut()
+            } else {
+                log.completed.last_mut()
+            }
+            .ok_or_else(|| eyre!("A break must come after the log line it belongs to."))?;
+            let end = match caps.name("end_time") {
+                Some(end_time) => Some(Time(OffsetDateTime::parse(
+                    end_time.as_str(),
+                    TIMESTAMP_FMT,
+                )?)),
+                None => None,
+            };
+            session.breaks.push(Break {
+                start: Time(OffsetDateTime::parse(&caps["start"], TIMESTAMP_FMT)?),
+                end,
+            });
+            continue;
+        }
         let caps = LOG_LINE_REGEX
             .captures(line)
             .ok_or_else(|| eyre!("Failed to parse log line"))?;
@@ -356,10 +1966,20 @@ fn parse_log_fmtd(fmtd: String) -> eyre::Result<Log> {
         let end_time = caps.name("end_time");
         let end_current = caps.name("end_current");
         let message = caps.name("message");
-
-        if end_current.is_some() && message.is_some() {
-            bail!("Log lines must not have a message if they are current.")
+        let id = caps
+            .name("id")
+            .map_or_else(String::new, |id| id.as_str().to_string());
+        if !id.is_empty() && log.sessions().any(|session| session.id == id) {
+            bail!("There can only be one log line with the ID `{}`.", id);
         }
+        let project = caps
+            .name("project")
+            .map(|project| project.as_str().to_string());
+        let tags = caps["tags"]
+            .split(" +")
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect::<BTreeSet<_>>();
 
         // cannot have both without some serious regex bugs
         if end_current.is_some() {
@@ -368,26 +1988,43 @@ fn parse_log_fmtd(fmtd: String) -> eyre::Result<Log> {
             }
 
             log.current = Some(Session {
+                id,
                 start: Time(OffsetDateTime::parse(start.as_str(), TIMESTAMP_FMT)?),
                 end: None,
-                message: None,
+                message: message.map(|message| message.as_str().to_string()),
+                project,
+                tags,
+                breaks: vec![],
+                extra: Map::new(),
             });
+            last_current = true;
         } else if let Some(end_time) = end_time {
             if message.is_none() {
                 bail!("A completed log must have a message.");
             }
 
             log.completed.push(Session {
+                id,
                 start: Time(OffsetDateTime::parse(start.as_str(), TIMESTAMP_FMT)?),
                 end: Some(Time(OffsetDateTime::parse(
                     end_time.as_str(),
                     TIMESTAMP_FMT,
                 )?)),
                 message: Some(message.unwrap().as_str().to_string()),
+                project,
+                tags,
+                breaks: vec![],
+                extra: Map::new(),
             });
+            last_current = false;
         }
     }
 
+    for session in log.sessions() {
+        validate_breaks(session)?;
+    }
+    log.assign_ids();
+
     Ok(log)
 }
 
@@ -421,3 +2058,356 @@ fn get_time() -> eyre::Result<OffsetDateTime> {
     // We don't need a lot of precision.
     Ok(OffsetDateTime::now_local()?.replace_nanosecond(0)?)
 }
+
+/// Get the time given by `at`, or the current time if there is none.
+fn get_time_at(at: Option<TimeSpec>) -> eyre::Result<OffsetDateTime> {
+    let now = get_time()?;
+    match at {
+        Some(at) => at.resolve(now),
+        None => Ok(now),
+    }
+}
+
+/// Parse a time of day, like `04:00`.
+fn parse_time_of_day(s: &str) -> eyre::Result<time::Time> {
+    AT_TIME_FMTS
+        .iter()
+        .find_map(|fmt| time::Time::parse(s.trim(), fmt).ok())
+        .ok_or_else(|| eyre!("Expected a time of day like `04:00`."))
+}
+
+/// A point in time given on the command line.
+#[derive(Copy, Clone, Debug)]
+enum TimeSpec {
+    /// A local time of day, today unless a date is given.
+    Absolute(Option<Date>, time::Time),
+    /// Some amount of time before now.
+    Ago(Duration),
+}
+
+const AT_DATETIME_FMTS: &[&[FormatItem]] = &[
+    format_description!("[year]-[month]-[day] [hour padding:none]:[minute]"),
+    format_description!("[year]-[month]-[day] [hour padding:none]:[minute]:[second]"),
+];
+const AT_DATE_FMT: &[FormatItem] = format_description!("[year]-[month]-[day]");
+const AT_TIME_FMTS: &[&[FormatItem]] = &[
+    format_description!("[hour padding:none]:[minute]"),
+    format_description!("[hour padding:none]:[minute]:[second]"),
+];
+
+/// Matches one component of a relative time, like `15m` or `10 minutes`.
+static RELATIVE_COMPONENT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"^([0-9]+) ?([a-z]+),? ?"#).unwrap());
+
+impl FromStr for TimeSpec {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        let s = s.trim();
+        if Date::parse(s, AT_DATE_FMT).is_ok() {
+            bail!(
+                "Expected a time of day along with the date, like `{} 09:00`.",
+                s
+            );
+        }
+        for fmt in AT_DATETIME_FMTS {
+            if let Ok(datetime) = PrimitiveDateTime::parse(s, fmt) {
+                return Ok(TimeSpec::Absolute(Some(datetime.date()), datetime.time()));
+            }
+        }
+        for fmt in AT_TIME_FMTS {
+            if let Ok(time) = time::Time::parse(s, fmt) {
+                return Ok(TimeSpec::Absolute(None, time));
+            }
+        }
+
+        let mut relative = match (s.strip_prefix('-'), s.strip_suffix(" ago")) {
+            (Some(relative), None) | (None, Some(relative)) => relative.trim(),
+            _ => bail!(
+                "Expected a time like `14:05`, `2026-10-15 09:00`, `-15m` or `10 minutes ago`."
+            ),
+        };
+        if relative.is_empty() {
+            bail!("Expected an amount of time, like `15m` or `10 minutes`.");
+        }
+        let mut duration = Duration::ZERO;
+        while !relative.is_empty() {
+            let caps = RELATIVE_COMPONENT_REGEX
+                .captures(relative)
+                .ok_or_else(|| eyre!("Failed to parse relative time `{}`", relative))?;
+            let amount = caps[1]
+                .parse::<i64>()
+                .wrap_err(eyre!("Relative time `{}` is too large", &caps[1]))?;
+            let unit = match &caps[2] {
+                "h" | "hr" | "hrs" | "hour" | "hours" => 60 * 60,
+                "m" | "min" | "mins" | "minute" | "minutes" => 60,
+                "s" | "sec" | "secs" | "second" | "seconds" => 1,
+                unit => bail!("Unknown unit of time `{}`", unit),
+            };
+            duration = amount
+                .checked_mul(unit)
+                .and_then(|seconds| duration.checked_add(Duration::seconds(seconds)))
+                .ok_or_else(|| eyre!("Relative time `{}` is too large", s))?;
+            relative = &relative[caps[0].len()..];
+        }
+        Ok(TimeSpec::Ago(duration))
+    }
+}
+
+impl TimeSpec {
+    /// Turn this into an actual time, relative to `now`.
+    fn resolve(self, now: OffsetDateTime) -> eyre::Result<OffsetDateTime> {
+        let time = match self {
+            // A time of day that is yet to come today, like `23:30` just
+            // after midnight, means yesterday.
+            TimeSpec::Absolute(None, time_of_day) if self.time_at(now)? > now => {
+                let yesterday = now.date().previous_day().unwrap_or(now.date());
+                TimeSpec::Absolute(Some(yesterday), time_of_day).time_at(now)?
+            }
+            _ => self.time_at(now)?,
+        };
+        if time > now {
+            bail!(
+                "The time `{}` is in the future.",
+                time.format(TIMESTAMP_FMT)?
+            );
+        }
+        Ok(time)
+    }
+
+    /// Turn this into an actual time, relative to `now`. Unlike
+    /// [`TimeSpec::resolve`], this can be in the future.
+    fn time_at(self, now: OffsetDateTime) -> eyre::Result<OffsetDateTime> {
+        Ok(match self {
+            TimeSpec::Absolute(date, time) => {
+                let datetime = PrimitiveDateTime::new(date.unwrap_or_else(|| now.date()), time);
+                // Use the offset in effect at that time, in case it was
+                // before a daylight saving time change.
+                let offset = UtcOffset::local_offset_at(datetime.assume_offset(now.offset()))
+                    .unwrap_or_else(|_| now.offset());
+                datetime.assume_offset(offset)
+            }
+            TimeSpec::Ago(duration) => now
+                .checked_sub(duration)
+                .ok_or_else(|| eyre!("Relative time is too large"))?,
+        })
+    }
+}
+
+/// A bound given to `--since` or `--until`, where a date alone is taken too.
+#[derive(Copy, Clone, Debug)]
+enum Bound {
+    Time(TimeSpec),
+    /// A date alone, meaning when that day starts.
+    Day(Date),
+}
+
+impl FromStr for Bound {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match Date::parse(s.trim(), AT_DATE_FMT) {
+            Ok(date) => Ok(Bound::Day(date)),
+            Err(_) => s.parse().map(Bound::Time),
+        }
+    }
+}
+
+impl Bound {
+    /// Turn this into an actual time, relative to `now`, with days starting
+    /// at `day_start`.
+    fn time_at(self, now: OffsetDateTime, day_start: time::Time) -> eyre::Result<OffsetDateTime> {
+        match self {
+            Bound::Time(time) => time.time_at(now),
+            Bound::Day(date) => TimeSpec::Absolute(Some(date), day_start).time_at(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    const SUNDAY: Calendar = Calendar {
+        week_start: WeekStart::Sunday,
+        day_start: time::Time::MIDNIGHT,
+    };
+
+    fn session(start: OffsetDateTime, end: OffsetDateTime) -> Session {
+        Session {
+            id: String::new(),
+            start: Time(start),
+            end: Some(Time(end)),
+            message: Some(String::new()),
+            project: None,
+            tags: BTreeSet::new(),
+            breaks: vec![],
+            extra: Map::new(),
+        }
+    }
+
+    #[test]
+    fn day() {
+        let day = Period::day(datetime!(2023-03-15 13:45:10 -5), &SUNDAY);
+        assert_eq!(day.start, datetime!(2023-03-15 00:00 -5));
+        assert_eq!(day.end, datetime!(2023-03-16 00:00 -5));
+    }
+
+    #[test]
+    fn week() {
+        // A Wednesday.
+        let week = Period::week(datetime!(2023-03-15 13:45:10 UTC), &SUNDAY);
+        assert_eq!(week.start, datetime!(2023-03-12 00:00 UTC));
+        assert_eq!(week.end, datetime!(2023-03-19 00:00 UTC));
+        // A Sunday is the first day of its own week.
+        let week = Period::week(datetime!(2023-03-12 00:00 UTC), &SUNDAY);
+        assert_eq!(week.start, datetime!(2023-03-12 00:00 UTC));
+    }
+
+    #[test]
+    fn across_midnight() {
+        let sess = session(
+            datetime!(2023-03-15 23:00 UTC),
+            datetime!(2023-03-16 01:00 UTC),
+        );
+        let first = Period::day(datetime!(2023-03-15 12:00 UTC), &SUNDAY);
+        let second = Period::day(datetime!(2023-03-16 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(first), Duration::hours(1));
+        assert_eq!(sess.elapsed_in(second), Duration::hours(1));
+    }
+
+    #[test]
+    fn across_week() {
+        // From Saturday night into Sunday morning.
+        let sess = session(
+            datetime!(2023-03-11 22:00 UTC),
+            datetime!(2023-03-12 01:30 UTC),
+        );
+        let week = Period::week(datetime!(2023-03-15 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(week), Duration::minutes(90));
+    }
+
+    #[test]
+    fn same_week_last_year() {
+        let sess = session(
+            datetime!(2022-03-16 09:00 UTC),
+            datetime!(2022-03-16 10:00 UTC),
+        );
+        assert_eq!(
+            sess.start.0.sunday_based_week(),
+            datetime!(2023-03-15 12:00 UTC).sunday_based_week()
+        );
+        let week = Period::week(datetime!(2023-03-15 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(week), Duration::ZERO);
+        let day = Period::day(datetime!(2023-03-16 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(day), Duration::ZERO);
+    }
+
+    #[test]
+    fn breaks_are_clipped() {
+        let mut sess = session(
+            datetime!(2023-03-15 22:00 UTC),
+            datetime!(2023-03-16 02:00 UTC),
+        );
+        sess.breaks.push(Break {
+            start: Time(datetime!(2023-03-15 23:30 UTC)),
+            end: Some(Time(datetime!(2023-03-16 00:15 UTC))),
+        });
+        let first = Period::day(datetime!(2023-03-15 12:00 UTC), &SUNDAY);
+        let second = Period::day(datetime!(2023-03-16 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(first), Duration::minutes(90));
+        assert_eq!(sess.elapsed_in(second), Duration::minutes(105));
+        assert_eq!(
+            sess.elapsed_in(first) + sess.elapsed_in(second),
+            sess.elapsed()
+        );
+    }
+
+    #[test]
+    fn other_offsets() {
+        // 23:00 to 01:00 in UTC-5 is 04:00 to 06:00 in UTC.
+        let sess = session(
+            datetime!(2023-03-15 23:00 -5),
+            datetime!(2023-03-16 01:00 -5),
+        );
+        let day = Period::day(datetime!(2023-03-16 12:00 UTC), &SUNDAY);
+        assert_eq!(sess.elapsed_in(day), Duration::hours(2));
+    }
+
+    #[test]
+    fn monday_weeks() {
+        let calendar = Calendar {
+            week_start: WeekStart::Monday,
+            ..SUNDAY
+        };
+        // A Wednesday.
+        let week = Period::week(datetime!(2023-03-15 13:45:10 UTC), &calendar);
+        assert_eq!(week.start, datetime!(2023-03-13 00:00 UTC));
+        assert_eq!(week.end, datetime!(2023-03-20 00:00 UTC));
+        // A Sunday is the last day of the week before.
+        let week = Period::week(datetime!(2023-03-19 23:00 UTC), &calendar);
+        assert_eq!(week.start, datetime!(2023-03-13 00:00 UTC));
+    }
+
+    #[test]
+    fn day_start() {
+        let calendar = Calendar {
+            day_start: time::macros::time!(04:00),
+            ..SUNDAY
+        };
+        let day = Period::day(datetime!(2023-03-16 02:30 UTC), &calendar);
+        assert_eq!(day.start, datetime!(2023-03-15 04:00 UTC));
+        assert_eq!(day.end, datetime!(2023-03-16 04:00 UTC));
+        let sess = session(
+            datetime!(2023-03-15 23:00 UTC),
+            datetime!(2023-03-16 01:00 UTC),
+        );
+        assert_eq!(sess.elapsed_in(day), Duration::hours(2));
+        // Early Sunday morning still belongs to the week before.
+        let week = Period::week(datetime!(2023-03-19 03:00 UTC), &calendar);
+        assert_eq!(week.start, datetime!(2023-03-12 04:00 UTC));
+    }
+
+    #[test]
+    fn month_and_year() {
+        let month = Period::month(datetime!(2023-12-31 23:00 -5), &SUNDAY);
+        assert_eq!(month.start, datetime!(2023-12-01 00:00 -5));
+        assert_eq!(month.end, datetime!(2024-01-01 00:00 -5));
+        let year = Period::year(datetime!(2023-03-15 13:45:10 UTC), &SUNDAY);
+        assert_eq!(year.start, datetime!(2023-01-01 00:00 UTC));
+        assert_eq!(year.end, datetime!(2024-01-01 00:00 UTC));
+    }
+
+    #[test]
+    fn assigned_ids_are_stable() {
+        let log = || {
+            let sess = session(
+                datetime!(2023-03-15 09:00 UTC),
+                datetime!(2023-03-15 10:00 UTC),
+            );
+            let mut log = Log {
+                completed: vec![sess.clone(), sess],
+                ..Log::default()
+            };
+            log.assign_ids();
+            log.completed
+                .into_iter()
+                .map(|session| session.id)
+                .collect::<Vec<_>>()
+        };
+        let ids = log();
+        assert_eq!(ids, log());
+        assert_ne!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn later_time_of_day_is_yesterday() {
+        let now = datetime!(2023-03-16 00:10 UTC);
+        let at = TimeSpec::Absolute(None, time::macros::time!(23:30));
+        assert_eq!(at.resolve(now).unwrap(), datetime!(2023-03-15 23:30 UTC));
+        let at = TimeSpec::Absolute(None, time::macros::time!(00:05));
+        assert_eq!(at.resolve(now).unwrap(), datetime!(2023-03-16 00:05 UTC));
+    }
+}
@@ -0,0 +1,192 @@
+//! Upgrading log files written by older versions of ttrk.
+//!
+//! Log files carry a `version` number, which is bumped whenever the format
+//! changes in a way older versions of ttrk would not understand. Log files
+//! from before there was a version are version 0.
+
+use std::collections::BTreeSet;
+
+use color_eyre::eyre::{self, bail, eyre};
+use serde_json::{Map, Value};
+
+/// A migration upgrades a log file from one version to the next.
+type Migration = fn(&mut Map<String, Value>) -> eyre::Result<()>;
+
+/// The migration at index `n` upgrades version `n` to version `n + 1`.
+const MIGRATIONS: &[Migration] = &[v0_to_v1];
+
+/// The version of the log file format this version of ttrk writes.
+pub const VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Upgrade a log file to the current version. The version number is taken out,
+/// so what is left is just the log.
+pub fn migrate(mut value: Value) -> eyre::Result<Value> {
+    let Value::Object(ref mut log) = value else {
+        bail!("The log file does not contain a log.");
+    };
+    let version = match log.remove("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| eyre!("The log file has an invalid version `{}`.", version))?,
+    };
+    if version > VERSION {
+        bail!(
+            "The log file is version {}, but this version of ttrk only understands up to \
+             version {}. Please update ttrk.",
+            version,
+            VERSION
+        );
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(log)?;
+    }
+    Ok(value)
+}
+
+/// Version 1 adds the version number. Everything else added to sessions
+/// before then (projects, tags and breaks) is optional, but sessions from
+/// before IDs existed are given one here.
+///
+/// The ID is made from the session itself, so that it stays the same every
+/// time the log is read, even by commands that never write the log back.
+fn v0_to_v1(log: &mut Map<String, Value>) -> eyre::Result<()> {
+    let mut sessions = vec![];
+    for (key, value) in log.iter_mut() {
+        match (key.as_str(), value) {
+            ("completed", Value::Array(completed)) => {
+                sessions.extend(completed.iter_mut().filter_map(Value::as_object_mut))
+            }
+            ("current", Value::Object(current)) => sessions.push(current),
+            _ => {}
+        }
+    }
+    let has_id = |session: &Map<String, Value>| {
+        session
+            .get("id")
+            .and_then(Value::as_str)
+            .is_some_and(|id| !id.is_empty())
+    };
+    let mut ids = sessions
+        .iter()
+        .filter(|session| has_id(session))
+        .map(|session| session["id"].as_str().unwrap().to_string())
+        .collect::<BTreeSet<_>>();
+    for session in sessions {
+        if has_id(session) {
+            continue;
+        }
+        let [start, end, message] =
+            ["start", "end", "message"].map(|field| session.get(field).unwrap_or(&Value::Null));
+        let id = derived_id([start, end, message], &mut ids);
+        session.insert("id".to_string(), Value::String(id));
+    }
+    Ok(())
+}
+
+/// An ID for a session without one, made up from its `start`, `end` and
+/// `message` as they are stored, so that the session gets the same ID every
+/// time the log is read. The ID is added to `ids`, the ones already taken.
+pub fn derived_id(fields: [&Value; 3], ids: &mut BTreeSet<String>) -> String {
+    let key = fields.map(Value::to_string).join("\0");
+    // Sessions that are the same, or just happen to hash the same, are told
+    // apart by the order they come in.
+    (0u32..)
+        .map(|attempt| format!("{:06x}", fnv1a(&format!("{}\0{}", key, attempt)) >> 40))
+        .find(|id| ids.insert(id.clone()))
+        .unwrap()
+}
+
+/// The 64-bit FNV-1a hash of `s`. Unlike the hashers in `std`, this is
+/// guaranteed to stay the same between releases.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Log;
+
+    fn load(fixture: &str) -> eyre::Result<Log> {
+        let value = serde_json::from_str(fixture)?;
+        Ok(serde_json::from_value(migrate(value)?)?)
+    }
+
+    #[test]
+    fn unversioned() {
+        let log = load(include_str!("../tests/fixtures/v0.json")).unwrap();
+        assert_eq!(log.completed.len(), 2);
+        assert_eq!(
+            log.completed[0].message.as_deref(),
+            Some("Write the parser")
+        );
+        let current = log.current.as_ref().unwrap();
+        assert!(current.end.is_none());
+        assert!(current.message.is_none());
+        let ids = log
+            .sessions()
+            .map(|session| &session.id)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.iter().all(|id| id.len() == 6));
+    }
+
+    #[test]
+    fn unversioned_ids_are_stable() {
+        let fixture = include_str!("../tests/fixtures/v0.json");
+        let ids = |log: Log| {
+            log.sessions()
+                .map(|session| session.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(load(fixture).unwrap()), ids(load(fixture).unwrap()));
+    }
+
+    #[test]
+    fn unversioned_duplicates_get_different_ids() {
+        let session = r#"{"start": "2022-06-01T09:00:00-04:00", "end": "2022-06-01T11:30:00-04:00", "message": "Same"}"#;
+        let log = load(&format!(
+            r#"{{"completed": [{0}, {0}], "current": null}}"#,
+            session
+        ))
+        .unwrap();
+        assert_ne!(log.completed[0].id, log.completed[1].id);
+    }
+
+    #[test]
+    fn unversioned_with_later_fields() {
+        let log = load(include_str!("../tests/fixtures/v0-later-fields.json")).unwrap();
+        let session = &log.completed[0];
+        assert_eq!(session.id, "0a1b2c");
+        assert_eq!(session.project.as_deref(), Some("ttrk"));
+        assert!(session.tags.contains("code"));
+        assert_eq!(session.breaks.len(), 1);
+        assert!(log.current.unwrap().paused().is_some());
+    }
+
+    #[test]
+    fn current() {
+        let value: Value = serde_json::from_str(include_str!("../tests/fixtures/v1.json")).unwrap();
+        let migrated = migrate(value.clone()).unwrap();
+        assert!(migrated.get("version").is_none());
+        let log: Log = serde_json::from_value(migrated).unwrap();
+        assert_eq!(log.completed.len(), 1);
+        assert_eq!(log.completed[0].id, "d4e5f6");
+    }
+
+    #[test]
+    fn newer() {
+        let err = load(include_str!("../tests/fixtures/newer.json")).unwrap_err();
+        assert!(err.to_string().contains("Please update ttrk"));
+    }
+
+    #[test]
+    fn invalid_version() {
+        let err = load(r#"{"version": "one", "completed": [], "current": null}"#).unwrap_err();
+        assert!(err.to_string().contains("invalid version"));
+        assert!(load("[]").is_err());
+    }
+}
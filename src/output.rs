@@ -0,0 +1,185 @@
+//! What `--format json` prints.
+//!
+//! This is kept apart from how the log file stores sessions, so that scripts
+//! can rely on it staying the same even when the log file format changes.
+//! Durations are in whole seconds, and times are RFC 3339 timestamps, like in
+//! the log file. The README describes each of these.
+
+use std::{collections::BTreeSet, io};
+
+use color_eyre::eyre;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{
+    report::{Grouping, Row},
+    Break, Session, Time,
+};
+
+/// Print `value` as JSON on a line of its own.
+pub fn print(value: &impl Serialize) -> eyre::Result<()> {
+    serde_json::to_writer_pretty(io::stdout(), value)?;
+    println!();
+    Ok(())
+}
+
+/// A session, completed or current.
+#[derive(Serialize)]
+pub struct SessionJson<'a> {
+    id: &'a str,
+    start: Time,
+    /// `null` for the current session.
+    end: Option<Time>,
+    /// The time spent on the session so far, leaving out breaks.
+    elapsed: i64,
+    /// Whether the session is on a break.
+    paused: bool,
+    message: Option<&'a str>,
+    project: Option<&'a str>,
+    tags: &'a BTreeSet<String>,
+    breaks: Vec<BreakJson>,
+}
+
+impl<'a> SessionJson<'a> {
+    pub fn new(session: &'a Session, now: OffsetDateTime) -> Self {
+        SessionJson {
+            id: &session.id,
+            start: session.start,
+            end: session.end,
+            elapsed: session
+                .elapsed_until(session.end.map_or(now, |end| end.0))
+                .whole_seconds(),
+            paused: session.paused().is_some(),
+            message: session.message.as_deref(),
+            project: session.project.as_deref(),
+            tags: &session.tags,
+            breaks: session
+                .breaks
+                .iter()
+                .map(|br| BreakJson::new(br, now))
+                .collect(),
+        }
+    }
+}
+
+/// A break taken during a session.
+#[derive(Serialize)]
+pub struct BreakJson {
+    start: Time,
+    /// `null` for the break the session is paused for.
+    end: Option<Time>,
+    /// How long the break went on for, or has so far.
+    elapsed: i64,
+}
+
+impl BreakJson {
+    fn new(br: &Break, now: OffsetDateTime) -> Self {
+        BreakJson {
+            start: br.start,
+            end: br.end,
+            elapsed: (br.end.map_or(now, |end| end.0) - br.start.0).whole_seconds(),
+        }
+    }
+}
+
+/// The output of `list`.
+#[derive(Serialize)]
+pub struct List<'a> {
+    pub sessions: Vec<SessionJson<'a>>,
+}
+
+/// The output of `status`. Totals only count completed sessions, and only the
+/// time spent within the range they were filtered to.
+#[derive(Serialize)]
+pub struct Status<'a> {
+    /// How many completed sessions there are.
+    pub completed: usize,
+    pub elapsed: Elapsed,
+    pub projects: Vec<ProjectTotal<'a>>,
+    pub tags: Vec<TagTotal<'a>>,
+    /// The most recent completed session.
+    pub last: Option<SessionJson<'a>>,
+    pub current: Option<SessionJson<'a>>,
+}
+
+#[derive(Serialize)]
+pub struct Elapsed {
+    pub total: i64,
+    pub today: i64,
+    pub week: i64,
+}
+
+/// The time spent on a project, or on sessions without one if `project` is
+/// `null`.
+#[derive(Serialize)]
+pub struct ProjectTotal<'a> {
+    pub project: Option<&'a str>,
+    pub elapsed: i64,
+}
+
+/// The time spent on sessions with a tag, or on untagged ones if `tag` is
+/// `null`.
+#[derive(Serialize)]
+pub struct TagTotal<'a> {
+    pub tag: Option<&'a str>,
+    pub elapsed: i64,
+}
+
+/// The output of `report`.
+#[derive(Serialize)]
+pub struct Report {
+    by: Grouping,
+    periods: Vec<PeriodTotal>,
+    total: Totals,
+}
+
+impl Report {
+    pub fn new(by: Grouping, rows: &[Row], total: &Row) -> Self {
+        Report {
+            by,
+            periods: rows
+                .iter()
+                .map(|row| {
+                    let period = row.period.unwrap();
+                    PeriodTotal {
+                        period: row.label.clone(),
+                        start: Time(period.start),
+                        end: Time(period.end),
+                        totals: Totals::new(row),
+                    }
+                })
+                .collect(),
+            total: Totals::new(total),
+        }
+    }
+}
+
+/// The time spent within one period of a report.
+#[derive(Serialize)]
+struct PeriodTotal {
+    /// The name of the period, as in the table `report` prints otherwise.
+    period: String,
+    start: Time,
+    end: Time,
+    #[serde(flatten)]
+    totals: Totals,
+}
+
+#[derive(Serialize)]
+struct Totals {
+    elapsed: i64,
+    hours: f64,
+    sessions: usize,
+    average: i64,
+}
+
+impl Totals {
+    fn new(row: &Row) -> Self {
+        Totals {
+            elapsed: row.total.whole_seconds(),
+            hours: row.hours(),
+            sessions: row.sessions,
+            average: row.average().whole_seconds(),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Salvaging what can be salvaged from a broken log file.
+
+use std::collections::BTreeSet;
+
+use color_eyre::{
+    eyre::{self, eyre},
+    Section,
+};
+use serde_json::{Deserializer, Value};
+
+use crate::{Log, Session};
+
+/// What could be salvaged from a broken log file.
+pub struct Salvaged {
+    pub log: Log,
+    /// What could not be salvaged, one problem per entry.
+    pub problems: Vec<String>,
+}
+
+/// An error for a log file that failed to parse at byte `position`, pointing
+/// the user to `repair`.
+pub fn parse_error(position: usize, e: impl Into<eyre::Report>) -> eyre::Report {
+    e.into()
+        .wrap_err(eyre!("Failed to parse log file at byte {}", position))
+        .suggestion("Run `ttrk repair` to recover what can be recovered from the log file.")
+}
+
+/// The byte position of `e` in `content`.
+pub fn error_position(content: &str, e: &serde_json::Error) -> usize {
+    let line_start = content
+        .split_inclusive('\n')
+        .take(e.line().saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>();
+    (line_start + e.column().saturating_sub(1)).min(content.len())
+}
+
+/// Find all the sessions in a JSON log that is cut short or mangled.
+///
+/// Rather than making sense of the log as a whole, this looks for every JSON
+/// object in it that is a session, and skips over anything that is not. What
+/// is left over is reported as a problem, unless it is only what holds the
+/// sessions together.
+pub fn salvage(content: &str) -> Salvaged {
+    let mut log = Log::default();
+    let mut problems = vec![];
+    let mut ids = BTreeSet::new();
+    let mut currents = vec![];
+    // Where the last session found ended.
+    let mut last_end = 0;
+    let mut position = 0;
+    while let Some(offset) = content[position..].find('{') {
+        let start = position + offset;
+        let mut values = Deserializer::from_str(&content[start..]).into_iter::<Value>();
+        let session = match values.next() {
+            Some(Ok(value)) if value.get("start").is_some() && value.get("message").is_some() => {
+                serde_json::from_value::<Session>(value)
+            }
+            _ => {
+                position = start + 1;
+                continue;
+            }
+        };
+        let end = start + values.byte_offset();
+        report_gap(content, last_end, start, &mut problems);
+        last_end = end;
+        position = end;
+        let mut session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                problems.push(format!("The session at byte {} is broken: {}", start, e));
+                continue;
+            }
+        };
+        // Sessions from before there were IDs all have an empty one.
+        if !session.id.is_empty() && !ids.insert(session.id.clone()) {
+            problems.push(format!(
+                "The session at byte {} has the same ID as another one, so it was given a new \
+                 one.",
+                start
+            ));
+            session.id.clear();
+        }
+        match session.end {
+            Some(_) if session.message.is_none() => problems.push(format!(
+                "The completed session at byte {} has no message.",
+                start
+            )),
+            Some(_) => log.completed.push(session),
+            None => currents.push((start, session)),
+        }
+    }
+    report_gap(content, last_end, content.len(), &mut problems);
+    // There can only be one current session. The latest one is the most likely
+    // to be the real one.
+    currents.sort_by_key(|(_, session)| session.start.0);
+    if let Some((_, current)) = currents.pop() {
+        log.current = Some(current);
+    }
+    for (start, _) in currents {
+        problems.push(format!(
+            "The session at byte {} is a second current session.",
+            start
+        ));
+    }
+    Salvaged { log, problems }
+}
+
+/// Report what is between sessions, from `start` to `end`, unless it is only
+/// what holds the sessions together.
+fn report_gap(content: &str, start: usize, end: usize, problems: &mut Vec<String>) {
+    let gap = &content[start..end];
+    let mut rest = gap.to_string();
+    for noise in [r#""completed""#, r#""current""#, r#""version""#, "null"] {
+        rest = rest.replace(noise, "");
+    }
+    let is_noise = |c: char| c.is_whitespace() || c.is_ascii_digit() || "[]{}:,".contains(c);
+    if rest.chars().all(is_noise) {
+        return;
+    }
+    let snippet = gap.trim_matches(is_noise);
+    let snippet = match snippet.char_indices().nth(60) {
+        Some((index, _)) => format!("{}...", &snippet[..index]),
+        None => snippet.to_string(),
+    };
+    problems.push(format!(
+        "Could not make sense of bytes {} to {}: {}",
+        start, end, snippet
+    ));
+}
@@ -0,0 +1,325 @@
+//! Totalling time spent over days, weeks, months or years.
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use clap::ValueEnum;
+use color_eyre::eyre;
+use serde::Serialize;
+use time::{macros::format_description, Duration, OffsetDateTime, UtcOffset};
+
+use crate::{display_duration, Calendar, Period, Session, WeekStart};
+
+/// What periods a report is split into.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Grouping {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Grouping {
+    /// The period `time` is in.
+    fn period(self, time: OffsetDateTime, calendar: &Calendar) -> Period {
+        match self {
+            Grouping::Day => Period::day(time, calendar),
+            Grouping::Week => Period::week(time, calendar),
+            Grouping::Month => Period::month(time, calendar),
+            Grouping::Year => Period::year(time, calendar),
+        }
+    }
+
+    /// A name for `period`, as shown in the report.
+    fn label(self, period: Period, calendar: &Calendar) -> eyre::Result<String> {
+        let date = period.start.date();
+        Ok(match self {
+            Grouping::Day => date.format(format_description!("[year]-[month]-[day]"))?,
+            Grouping::Week if calendar.week_start == WeekStart::Iso => {
+                let (year, week, _) = date.to_iso_week_date();
+                format!("{}-W{:02}", year, week)
+            }
+            Grouping::Week => format!(
+                "Week of {}",
+                date.format(format_description!("[year]-[month]-[day]"))?
+            ),
+            Grouping::Month => date.format(format_description!("[year]-[month]"))?,
+            Grouping::Year => date.year().to_string(),
+        })
+    }
+}
+
+/// The time spent within one period, or over all of them.
+pub struct Row {
+    pub label: String,
+    /// `None` for the grand total.
+    pub period: Option<Period>,
+    pub total: Duration,
+    /// How many sessions time was spent in. A session that goes on over more
+    /// than one period counts toward each of them.
+    pub sessions: usize,
+}
+
+impl Row {
+    /// The average time spent per session.
+    pub fn average(&self) -> Duration {
+        match self.sessions {
+            0 => Duration::ZERO,
+            sessions => self.total / sessions as u32,
+        }
+    }
+
+    /// The total in hours.
+    pub fn hours(&self) -> f64 {
+        self.total.as_seconds_f64() / 3600.0
+    }
+}
+
+/// The time spent in completed `sessions`, split up by `grouping`, only
+/// counting time between `from` and `to`. Periods without any time spent are
+/// left out. Also returns the grand total.
+pub fn report(
+    sessions: &[&Session],
+    grouping: Grouping,
+    calendar: &Calendar,
+    range: (Option<OffsetDateTime>, Option<OffsetDateTime>),
+    offset: UtcOffset,
+) -> eyre::Result<(Vec<Row>, Row)> {
+    let mut periods = BTreeMap::<OffsetDateTime, (Period, Duration, usize)>::new();
+    let mut total = Row {
+        label: "Total".to_string(),
+        period: None,
+        total: Duration::ZERO,
+        sessions: 0,
+    };
+    // In local time, sessions from before or after a daylight saving time
+    // change are taken in the offset in effect then.
+    let local = UtcOffset::current_local_offset() == Ok(offset);
+    for session in sessions.iter().filter(|session| session.end.is_some()) {
+        let offset = match UtcOffset::local_offset_at(session.start.0) {
+            Ok(then) if local => then,
+            _ => offset,
+        };
+        let start = session.start.0.to_offset(offset);
+        let end = session.end.unwrap().0;
+        let mut period = grouping.period(start, calendar);
+        let mut elapsed_total = Duration::ZERO;
+        while period.start < end {
+            let elapsed = session.elapsed_in(period.within(range));
+            if elapsed.is_positive() {
+                let entry = periods
+                    .entry(period.start)
+                    .or_insert((period, Duration::ZERO, 0));
+                entry.1 += elapsed;
+                entry.2 += 1;
+                elapsed_total += elapsed;
+            }
+            period = grouping.period(period.end, calendar);
+        }
+        if elapsed_total.is_positive() {
+            total.total += elapsed_total;
+            total.sessions += 1;
+        }
+    }
+    let rows = periods
+        .into_values()
+        .map(|(period, total, sessions)| {
+            Ok(Row {
+                label: grouping.label(period, calendar)?,
+                period: Some(period),
+                total,
+                sessions,
+            })
+        })
+        .collect::<eyre::Result<_>>()?;
+    Ok((rows, total))
+}
+
+/// Lay out a report as a table, with the grand total at the bottom.
+pub fn format_report(rows: &[Row], total: &Row) -> eyre::Result<String> {
+    let header = ["Period", "Total", "Hours", "Sessions", "Average"];
+    let cells = rows
+        .iter()
+        .chain([total])
+        .map(|row| {
+            [
+                row.label.clone(),
+                display_duration(row.total),
+                format!("{:.2}", row.hours()),
+                row.sessions.to_string(),
+                display_duration(row.average()),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = header.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut s = String::new();
+    let write_row = |s: &mut String, row: [&str; 5]| {
+        // Numbers line up on the right, everything else on the left.
+        writeln!(
+            s,
+            "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        )
+    };
+    write_row(&mut s, header)?;
+    for (index, row) in cells.iter().enumerate() {
+        if index == rows.len() {
+            writeln!(s, "{}", "-".repeat(widths.iter().sum::<usize>() + 2 * 4))?;
+        }
+        write_row(&mut s, row.each_ref().map(String::as_str))?;
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::Map;
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::Time;
+
+    const CALENDAR: Calendar = Calendar {
+        week_start: WeekStart::Iso,
+        day_start: time::Time::MIDNIGHT,
+    };
+
+    fn session(start: OffsetDateTime, end: Option<OffsetDateTime>) -> Session {
+        Session {
+            id: String::new(),
+            start: Time(start),
+            end: end.map(Time),
+            message: Some(String::new()),
+            project: None,
+            tags: BTreeSet::new(),
+            breaks: vec![],
+            extra: Map::new(),
+        }
+    }
+
+    fn sessions() -> Vec<Session> {
+        vec![
+            session(
+                datetime!(2023-03-15 09:00 UTC),
+                Some(datetime!(2023-03-15 10:00 UTC)),
+            ),
+            // Goes on past midnight.
+            session(
+                datetime!(2023-03-15 23:00 UTC),
+                Some(datetime!(2023-03-16 01:00 UTC)),
+            ),
+            session(
+                datetime!(2023-03-16 12:00 UTC),
+                Some(datetime!(2023-03-16 12:30 UTC)),
+            ),
+            // The current session is left out.
+            session(datetime!(2023-03-16 13:00 UTC), None),
+        ]
+    }
+
+    fn summary(row: &Row) -> (&str, Duration, usize, Duration) {
+        (&row.label, row.total, row.sessions, row.average())
+    }
+
+    #[test]
+    fn by_day() {
+        let sessions = sessions();
+        let sessions = sessions.iter().collect::<Vec<_>>();
+        let (rows, total) = report(
+            &sessions,
+            Grouping::Day,
+            &CALENDAR,
+            (None, None),
+            UtcOffset::UTC,
+        )
+        .unwrap();
+        assert_eq!(
+            rows.iter().map(summary).collect::<Vec<_>>(),
+            [
+                ("2023-03-15", Duration::hours(2), 2, Duration::hours(1)),
+                (
+                    "2023-03-16",
+                    Duration::minutes(90),
+                    2,
+                    Duration::minutes(45)
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(&total),
+            ("Total", Duration::minutes(210), 3, Duration::minutes(70))
+        );
+    }
+
+    #[test]
+    fn clipped_to_range() {
+        let sessions = sessions();
+        let sessions = sessions.iter().collect::<Vec<_>>();
+        let (rows, total) = report(
+            &sessions,
+            Grouping::Day,
+            &CALENDAR,
+            (Some(datetime!(2023-03-15 23:30 UTC)), None),
+            UtcOffset::UTC,
+        )
+        .unwrap();
+        assert_eq!(
+            rows.iter().map(summary).collect::<Vec<_>>(),
+            [
+                (
+                    "2023-03-15",
+                    Duration::minutes(30),
+                    1,
+                    Duration::minutes(30)
+                ),
+                (
+                    "2023-03-16",
+                    Duration::minutes(90),
+                    2,
+                    Duration::minutes(45)
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(&total),
+            ("Total", Duration::hours(2), 2, Duration::hours(1))
+        );
+    }
+
+    #[test]
+    fn by_iso_week() {
+        let sessions = sessions();
+        let sessions = sessions.iter().collect::<Vec<_>>();
+        let (rows, _) = report(
+            &sessions,
+            Grouping::Week,
+            &CALENDAR,
+            (None, None),
+            UtcOffset::UTC,
+        )
+        .unwrap();
+        assert_eq!(
+            rows.iter().map(summary).collect::<Vec<_>>(),
+            [("2023-W11", Duration::minutes(210), 3, Duration::minutes(70))]
+        );
+        let period = rows[0].period.unwrap();
+        assert_eq!(period.start, datetime!(2023-03-13 00:00 UTC));
+        assert_eq!(period.end, datetime!(2023-03-20 00:00 UTC));
+    }
+}
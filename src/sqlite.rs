@@ -0,0 +1,332 @@
+//! Storing the log in an SQLite database.
+//!
+//! Each session is a row of its own, so changing the log only touches the
+//! sessions that changed, and sessions can be looked up by ID, time, project
+//! or tag without going through all of them.
+
+use std::{collections::HashMap, path::Path};
+
+use color_eyre::eyre::{self, bail, eyre, Context};
+use rusqlite::{
+    params, params_from_iter, types, Connection, OpenFlags, OptionalExtension, Transaction,
+};
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+
+use crate::{
+    migrations,
+    storage::{Backend, Query, Storage},
+    Log, Session, Time,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    -- JSON
+    value TEXT NOT NULL
+);
+-- The current session is the one without an end.
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    -- Where a completed session comes in the log.
+    position INTEGER,
+    start TEXT NOT NULL,
+    end TEXT,
+    message TEXT,
+    project TEXT,
+    -- JSON
+    breaks TEXT NOT NULL,
+    -- Fields ttrk does not know about, as a JSON object.
+    extra TEXT NOT NULL
+);
+-- Times have offsets, so they are compared as Unix times.
+CREATE INDEX sessions_start ON sessions (unixepoch(start));
+CREATE INDEX sessions_end ON sessions (unixepoch(end));
+CREATE INDEX sessions_project ON sessions (project);
+CREATE TABLE tags (
+    session TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (session, tag)
+);
+CREATE INDEX tags_tag ON tags (tag);
+";
+
+/// The log as an SQLite database.
+#[derive(Default)]
+pub struct Sqlite {
+    /// Whether the database is from this version, so that sessions from this
+    /// version can be written to it as they are.
+    up_to_date: bool,
+}
+
+impl Backend for Sqlite {
+    fn storage(&self) -> Storage {
+        Storage::Sqlite
+    }
+
+    fn read(&mut self, path: &Path) -> eyre::Result<Log> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .wrap_err(eyre!("Failed to open log file at `{}`", path.display()))?;
+        let version = get_meta(&conn, "version")?.unwrap_or(Value::from(0));
+        self.up_to_date = version.as_u64() == Some(migrations::VERSION);
+        let mut log = match get_meta(&conn, "extra")? {
+            Some(Value::Object(extra)) => extra,
+            _ => Map::new(),
+        };
+        log.insert("version".to_string(), version);
+
+        let mut completed = vec![];
+        let mut current = Value::Null;
+        for session in read_sessions(&conn, "TRUE", vec![])? {
+            match session.get("end") {
+                Some(Value::Null) if current.is_null() => current = Value::Object(session),
+                Some(Value::Null) => bail!("The log file has more than one current session."),
+                _ => completed.push(Value::Object(session)),
+            }
+        }
+        log.insert("completed".to_string(), Value::Array(completed));
+        log.insert("current".to_string(), current);
+
+        let log = migrations::migrate(Value::Object(log))?;
+        serde_json::from_value(log).wrap_err(eyre!("Failed to parse log file"))
+    }
+
+    fn update(&mut self, path: &Path, before: &Log, after: &Log) -> eyre::Result<bool> {
+        if !self.up_to_date {
+            return Ok(false);
+        }
+        let mut conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)
+            .wrap_err(eyre!("Failed to open log file at `{}`", path.display()))?;
+        let tx = conn.transaction()?;
+        let old = positions(before)
+            .map(|(position, session)| (&session.id, (position, session)))
+            .collect::<HashMap<_, _>>();
+        for (position, session) in positions(after) {
+            let unchanged = match old.get(&session.id) {
+                Some(&(old_position, old)) => {
+                    old_position == position
+                        && serde_json::to_value(old)? == serde_json::to_value(session)?
+                }
+                None => false,
+            };
+            if !unchanged {
+                put_session(&tx, position, session)?;
+            }
+        }
+        for session in before.sessions() {
+            if !after.sessions().any(|other| other.id == session.id) {
+                tx.execute("DELETE FROM sessions WHERE id = ?1", [&session.id])?;
+                tx.execute("DELETE FROM tags WHERE session = ?1", [&session.id])?;
+            }
+        }
+        if before.extra != after.extra {
+            put_meta(&tx, "extra", &Value::Object(after.extra.clone()))?;
+        }
+        tx.commit()
+            .wrap_err(eyre!("Failed to write to log file at `{}`", path.display()))?;
+        Ok(true)
+    }
+
+    fn create(&mut self, path: &Path, log: &Log) -> eyre::Result<()> {
+        let mut conn = Connection::open(path)?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(SCHEMA)?;
+        put_meta(&tx, "version", &Value::from(migrations::VERSION))?;
+        put_meta(&tx, "extra", &Value::Object(log.extra.clone()))?;
+        for (position, session) in positions(log) {
+            put_session(&tx, position, session)?;
+        }
+        tx.commit()?;
+        self.up_to_date = true;
+        Ok(())
+    }
+
+    fn query(&mut self, path: &Path, query: &Query) -> eyre::Result<Option<Vec<Session>>> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .wrap_err(eyre!("Failed to open log file at `{}`", path.display()))?;
+        // Sessions from older versions have to be migrated along with the
+        // rest of the log.
+        let version = get_meta(&conn, "version")?.unwrap_or(Value::from(0));
+        if version.as_u64() != Some(migrations::VERSION) {
+            return Ok(None);
+        }
+
+        let mut conditions = vec![];
+        let mut params = vec![];
+        let mut param = |value: types::Value| {
+            params.push(value);
+            format!("?{}", params.len())
+        };
+        if let Some(ref id) = query.id {
+            // Escape what GLOB treats specially by putting it in brackets.
+            let mut pattern = String::new();
+            for c in id.chars() {
+                match c {
+                    '*' | '?' | '[' => pattern.extend(['[', c, ']']),
+                    c => pattern.push(c),
+                }
+            }
+            pattern.push('*');
+            conditions.push(format!("id GLOB {}", param(pattern.into())));
+        }
+        if let Some(ref project) = query.project {
+            conditions.push(format!("project = {}", param(project.clone().into())));
+        }
+        for tag in &query.tags {
+            conditions.push(format!(
+                "id IN (SELECT session FROM tags WHERE tag = {})",
+                param(tag.clone().into())
+            ));
+        }
+        for tag in &query.no_tags {
+            conditions.push(format!(
+                "id NOT IN (SELECT session FROM tags WHERE tag = {})",
+                param(tag.clone().into())
+            ));
+        }
+        if let Some(from) = query.from {
+            conditions.push(format!(
+                "(end IS NULL OR unixepoch(end) > {})",
+                param(from.unix_timestamp().into())
+            ));
+        }
+        if let Some(to) = query.to {
+            conditions.push(format!(
+                "unixepoch(start) < {}",
+                param(to.unix_timestamp().into())
+            ));
+        }
+        if query.completed_only {
+            conditions.push("end IS NOT NULL".to_string());
+        }
+        if conditions.is_empty() {
+            conditions.push("TRUE".to_string());
+        }
+
+        read_sessions(&conn, &conditions.join(" AND "), params)?
+            .into_iter()
+            .map(|session| {
+                serde_json::from_value(Value::Object(session))
+                    .wrap_err(eyre!("Failed to parse log file"))
+            })
+            .collect::<eyre::Result<_>>()
+            .map(Some)
+    }
+}
+
+/// Read the sessions that match `condition` as they are stored, completed
+/// sessions in order and then the current session.
+fn read_sessions(
+    conn: &Connection,
+    condition: &str,
+    params: Vec<types::Value>,
+) -> eyre::Result<Vec<Map<String, Value>>> {
+    let mut tags = HashMap::<String, Vec<Value>>::new();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT session, tag FROM tags
+         WHERE session IN (SELECT id FROM sessions WHERE {})
+         ORDER BY tag",
+        condition
+    ))?;
+    let mut rows = stmt.query(params_from_iter(&params))?;
+    while let Some(row) = rows.next()? {
+        tags.entry(row.get(0)?)
+            .or_default()
+            .push(Value::String(row.get(1)?));
+    }
+
+    let mut sessions = vec![];
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, start, end, message, project, breaks, extra
+         FROM sessions WHERE {}
+         ORDER BY position IS NULL, position",
+        condition
+    ))?;
+    let mut rows = stmt.query(params_from_iter(&params))?;
+    while let Some(row) = rows.next()? {
+        let mut session = match serde_json::from_str(&row.get::<_, String>(6)?)? {
+            Value::Object(extra) => extra,
+            _ => Map::new(),
+        };
+        let id: String = row.get(0)?;
+        let project: Option<String> = row.get(4)?;
+        if let Some(tags) = tags.remove(&id) {
+            session.insert("tags".to_string(), Value::Array(tags));
+        }
+        if let Some(project) = project {
+            session.insert("project".to_string(), Value::String(project));
+        }
+        session.insert("id".to_string(), Value::String(id));
+        session.insert("start".to_string(), Value::String(row.get(1)?));
+        session.insert(
+            "end".to_string(),
+            Value::from(row.get::<_, Option<String>>(2)?),
+        );
+        session.insert(
+            "message".to_string(),
+            Value::from(row.get::<_, Option<String>>(3)?),
+        );
+        session.insert(
+            "breaks".to_string(),
+            serde_json::from_str(&row.get::<_, String>(5)?)?,
+        );
+        sessions.push(session);
+    }
+    Ok(sessions)
+}
+
+/// The sessions of `log`, along with their positions. The current session
+/// has none.
+fn positions(log: &Log) -> impl Iterator<Item = (Option<usize>, &Session)> {
+    log.completed
+        .iter()
+        .enumerate()
+        .map(|(position, session)| (Some(position), session))
+        .chain(log.current.iter().map(|session| (None, session)))
+}
+
+fn put_session(tx: &Transaction, position: Option<usize>, session: &Session) -> eyre::Result<()> {
+    let format = |time: Time| time.0.format(&Rfc3339);
+    tx.execute(
+        "INSERT OR REPLACE INTO sessions
+         (id, position, start, end, message, project, breaks, extra)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            session.id,
+            position,
+            format(session.start)?,
+            session.end.map(format).transpose()?,
+            session.message,
+            session.project,
+            serde_json::to_string(&session.breaks)?,
+            serde_json::to_string(&session.extra)?,
+        ],
+    )?;
+    tx.execute("DELETE FROM tags WHERE session = ?1", [&session.id])?;
+    for tag in &session.tags {
+        tx.execute(
+            "INSERT INTO tags (session, tag) VALUES (?1, ?2)",
+            [&session.id, tag],
+        )?;
+    }
+    Ok(())
+}
+
+fn get_meta(conn: &Connection, key: &str) -> eyre::Result<Option<Value>> {
+    let value = conn
+        .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()?;
+    Ok(value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()?)
+}
+
+fn put_meta(tx: &Transaction, key: &str, value: &Value) -> eyre::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, serde_json::to_string(value)?],
+    )?;
+    Ok(())
+}
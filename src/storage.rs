@@ -0,0 +1,645 @@
+//! Reading and writing the log file.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, TryLockError},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{self, bail, eyre, Context};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use tempfile::NamedTempFile;
+use time::OffsetDateTime;
+use tracing::info;
+
+use crate::{
+    events::{self, EventLog},
+    migrations,
+    repair::{self, Salvaged},
+    sqlite::Sqlite,
+    Log, Session,
+};
+
+/// An open log file.
+///
+/// While this is around, a lock is held on a `.lock` file next to the log
+/// file, so that other ttrk processes cannot change the log file out from
+/// under us. Read-only log files only take a shared lock, and go without one
+/// if the lock file cannot be opened.
+pub struct LogFile {
+    path: PathBuf,
+    lock: Option<File>,
+    read_only: bool,
+    /// How many backups to keep.
+    backups: usize,
+    /// How to store the log from now on.
+    storage: Storage,
+    /// How the log is stored right now, or `None` if there is no log file.
+    backend: Option<Box<dyn Backend>>,
+    /// The log as it was read or last written, for telling what changed.
+    log: Log,
+}
+
+/// How the log is stored in the log file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Storage {
+    /// One JSON document, rewritten whenever the log changes.
+    Json,
+    /// One JSON line per change, appended to the log file. Run `compact`
+    /// every now and then to keep it from growing too big.
+    Events,
+    /// An SQLite database, where only the sessions that changed are written,
+    /// and commands that only look sessions up read just the ones they need.
+    Sqlite,
+}
+
+/// A way of storing the log in the log file.
+pub trait Backend {
+    fn storage(&self) -> Storage;
+
+    /// Read the log from the log file at `path`.
+    fn read(&mut self, path: &Path) -> eyre::Result<Log>;
+
+    /// Change the log in the log file at `path` from `before` to `after`, if
+    /// that can be done without rewriting all of it. Returns whether it was
+    /// done.
+    fn update(&mut self, path: &Path, before: &Log, after: &Log) -> eyre::Result<bool>;
+
+    /// Write `log` to the new, empty file at `path`.
+    fn create(&mut self, path: &Path, log: &Log) -> eyre::Result<()>;
+
+    /// Look up the sessions that may match `query` in the log file at `path`,
+    /// without reading all of it. Sessions that do not match after all are
+    /// left out afterwards, so only some of `query` needs to be used. Returns
+    /// `None` if that cannot be done, in which case the whole log is read
+    /// instead.
+    fn query(&mut self, _path: &Path, _query: &Query) -> eyre::Result<Option<Vec<Session>>> {
+        Ok(None)
+    }
+}
+
+/// Which sessions to look up with [`LogFile::query`]. Sessions have to match
+/// all of it.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// A prefix of the session's ID.
+    pub id: Option<String>,
+    pub project: Option<String>,
+    /// Tags the session has to have.
+    pub tags: Vec<String>,
+    /// Tags the session must not have.
+    pub no_tags: Vec<String>,
+    /// A regex the session's message has to match. Sessions without a
+    /// message are matched as if their message were empty.
+    pub grep: Option<Regex>,
+    /// A time the session has to end after.
+    pub from: Option<OffsetDateTime>,
+    /// A time the session has to start before.
+    pub to: Option<OffsetDateTime>,
+    /// When the current session counts as ending, for `from`. Without it,
+    /// the current session always ends after `from`.
+    pub now: Option<OffsetDateTime>,
+    /// Whether to leave out the current session.
+    pub completed_only: bool,
+}
+
+impl Query {
+    pub fn matches(&self, session: &Session) -> bool {
+        self.id.as_ref().is_none_or(|id| session.id.starts_with(id))
+            && self
+                .project
+                .as_ref()
+                .is_none_or(|project| session.project.as_ref() == Some(project))
+            && self.tags.iter().all(|tag| session.tags.contains(tag))
+            && !self.no_tags.iter().any(|tag| session.tags.contains(tag))
+            && self
+                .grep
+                .as_ref()
+                .is_none_or(|grep| grep.is_match(session.message.as_deref().unwrap_or_default()))
+            && self.from.is_none_or(|from| {
+                session
+                    .end
+                    .map(|end| end.0)
+                    .or(self.now)
+                    .is_none_or(|end| end > from)
+            })
+            && self.to.is_none_or(|to| session.start.0 < to)
+            && !(self.completed_only && session.end.is_none())
+    }
+}
+
+impl Storage {
+    fn backend(self) -> Box<dyn Backend> {
+        match self {
+            Storage::Json => Box::new(Document),
+            Storage::Events => Box::<EventLog>::default(),
+            Storage::Sqlite => Box::<Sqlite>::default(),
+        }
+    }
+
+    /// Tell how the file at `path` is stored from how it starts, or `None` if
+    /// it is missing or empty.
+    fn detect(path: &Path) -> eyre::Result<Option<Storage>> {
+        let mut start = vec![];
+        match File::open(path) {
+            Ok(file) => file.take(64).read_to_end(&mut start),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e),
+        }
+        .wrap_err(eyre!("Failed to read log file at `{}`", path.display()))?;
+        Ok(if start.is_empty() {
+            None
+        } else if start.starts_with(b"SQLite format 3\0") {
+            Some(Storage::Sqlite)
+        } else if start.starts_with(events::HEADER.as_bytes())
+            // Event logs from before they had a header start with a snapshot.
+            || start.trim_ascii_start().starts_with(br#"{"op""#)
+        {
+            Some(Storage::Events)
+        } else {
+            Some(Storage::Json)
+        })
+    }
+}
+
+impl LogFile {
+    /// Lock and read the log file at `path`. A missing or empty log file is
+    /// an empty log.
+    ///
+    /// The log is stored as it already is, unless `storage` says otherwise,
+    /// in which case it is converted the next time it is written.
+    pub fn open(
+        path: &Path,
+        read_only: bool,
+        backups: usize,
+        storage: Option<Storage>,
+    ) -> eyre::Result<(LogFile, Log)> {
+        let mut logfile = LogFile::lock_only(path, read_only, backups)?;
+        let (backend, log) = read(&logfile.path)?;
+        if backend.is_some() {
+            info!("Using log file at `{}`", logfile.path.display());
+        }
+        logfile.backend = backend;
+        logfile.storage = storage
+            .or_else(|| logfile.stored_as())
+            .unwrap_or(Storage::Json);
+        logfile.log = log.clone();
+        Ok((logfile, log))
+    }
+
+    /// Lock the log file at `path` as read-only, only for looking sessions up
+    /// with [`LogFile::query`].
+    ///
+    /// As with [`LogFile::open`], a log file stored another way than
+    /// `storage` says is read as it is, but it is never converted.
+    pub fn open_for_queries(
+        path: &Path,
+        backups: usize,
+        storage: Option<Storage>,
+    ) -> eyre::Result<LogFile> {
+        let mut logfile = LogFile::lock_only(path, true, backups)?;
+        logfile.backend = Storage::detect(&logfile.path)?.map(Storage::backend);
+        if logfile.backend.is_some() {
+            info!("Using log file at `{}`", logfile.path.display());
+        }
+        logfile.storage = storage
+            .or_else(|| logfile.stored_as())
+            .unwrap_or(Storage::Json);
+        Ok(logfile)
+    }
+
+    /// Lock the log file at `path` without reading it, e.g. because it is
+    /// broken. See [`LogFile::salvage`].
+    pub fn open_broken(
+        path: &Path,
+        backups: usize,
+        storage: Option<Storage>,
+    ) -> eyre::Result<LogFile> {
+        let mut logfile = LogFile::lock_only(path, false, backups)?;
+        logfile.storage = match storage {
+            Some(storage) => storage,
+            None => Storage::detect(&logfile.path)?.unwrap_or(Storage::Json),
+        };
+        Ok(logfile)
+    }
+
+    fn lock_only(path: &Path, read_only: bool, backups: usize) -> eyre::Result<LogFile> {
+        let path = resolve_symlinks(path)?;
+        let mut lock_path = OsString::from(path.as_os_str());
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let lock = if read_only {
+            File::open(&lock_path).ok()
+        } else {
+            Some(
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&lock_path)
+                    .wrap_err(eyre!(
+                        "Failed to open lock file at `{}`",
+                        lock_path.display()
+                    ))?,
+            )
+        };
+        let mut logfile = LogFile {
+            path,
+            lock,
+            read_only,
+            backups,
+            storage: Storage::Json,
+            backend: None,
+            log: Log::default(),
+        };
+        logfile.lock()?;
+        Ok(logfile)
+    }
+
+    /// Salvage what can be salvaged from the log file, if it is broken.
+    /// Returns `None` if it is not.
+    pub fn salvage(&self) -> eyre::Result<Option<Salvaged>> {
+        let Some(storage) = Storage::detect(&self.path)? else {
+            return Ok(None);
+        };
+        if storage == Storage::Sqlite {
+            bail!("Only log files stored as JSON or as events can be repaired.");
+        }
+        if storage.backend().read(&self.path).is_ok() {
+            return Ok(None);
+        }
+        let content = fs::read(&self.path).wrap_err(eyre!(
+            "Failed to read log file at `{}`",
+            self.path.display()
+        ))?;
+        let content = String::from_utf8_lossy(&content);
+        // A log file from a newer version of ttrk is not broken, it is just
+        // not understood, and salvaging it would lose what is not understood.
+        let first_line = content.lines().next().unwrap_or_default();
+        if let Ok(value) = serde_json::from_str::<Value>(first_line) {
+            if value.get("version").and_then(Value::as_u64) > Some(migrations::VERSION) {
+                migrations::migrate(value)?;
+            }
+        }
+        Ok(Some(match storage {
+            Storage::Events => events::salvage(&content),
+            _ => repair::salvage(&content),
+        }))
+    }
+
+    /// Look up the sessions that match `query`, in the order they are in the
+    /// log.
+    ///
+    /// If the log file is stored in a way that allows for it, only those
+    /// sessions are read. Otherwise, the whole log is read.
+    pub fn query(&mut self, query: &Query) -> eyre::Result<Vec<Session>> {
+        let Some(ref mut backend) = self.backend else {
+            return Ok(vec![]);
+        };
+        let sessions = match backend.query(&self.path, query)? {
+            Some(sessions) => sessions,
+            None => {
+                let mut log = backend.read(&self.path)?;
+                log.assign_ids();
+                log.completed.into_iter().chain(log.current).collect()
+            }
+        };
+        Ok(sessions
+            .into_iter()
+            .filter(|session| query.matches(session))
+            .collect())
+    }
+
+    /// Write `log` to the log file.
+    ///
+    /// The old log file is backed up first. Then, if the log file is already
+    /// stored as it should be, and its storage allows for it, only what
+    /// changed is written. Otherwise the log file is replaced.
+    ///
+    /// Nothing is written if the log file already holds `log`, stored as it
+    /// should be, e.g. because it was just rewritten.
+    pub fn write(&mut self, log: &Log) -> eyre::Result<()> {
+        let unchanged = self.stored_as() == Some(self.storage)
+            && serde_json::to_value(log)? == serde_json::to_value(&self.log)?;
+        if unchanged {
+            return Ok(());
+        }
+        let existed = self.check_writable()?;
+        if existed {
+            self.back_up()?;
+        }
+        let updated = match self.backend {
+            Some(ref mut backend) if backend.storage() == self.storage => {
+                backend.update(&self.path, &self.log, log)?
+            }
+            _ => false,
+        };
+        if !updated {
+            return self.replace(log, existed);
+        }
+        self.log = log.clone();
+        Ok(())
+    }
+
+    /// Replace the log file with `log`, backing up the old one first. An event
+    /// log is folded into a single snapshot.
+    pub fn rewrite(&mut self, log: &Log) -> eyre::Result<()> {
+        let existed = self.check_writable()?;
+        if existed {
+            self.back_up()?;
+        }
+        self.replace(log, existed)
+    }
+
+    /// Replace the log file with `log`, without backing it up. `existed` is
+    /// whether there was a log file before.
+    fn replace(&mut self, log: &Log, existed: bool) -> eyre::Result<()> {
+        let mut backend = self.storage.backend();
+        replace_with(&self.path, |path| backend.create(path, log))?;
+        if !existed {
+            info!("Created log file at `{}`", self.path.display());
+        }
+        self.backend = Some(backend);
+        self.log = log.clone();
+        Ok(())
+    }
+
+    /// Store the log as `storage` from now on, converting the log file right
+    /// away.
+    pub fn convert(&mut self, storage: Storage, log: &Log) -> eyre::Result<()> {
+        self.storage = storage;
+        self.rewrite(log)
+    }
+
+    /// How the log is stored in the log file right now, if there is one.
+    pub fn stored_as(&self) -> Option<Storage> {
+        self.backend.as_ref().map(|backend| backend.storage())
+    }
+
+    /// Make sure the log file can be written to, returning whether it exists.
+    fn check_writable(&self) -> eyre::Result<bool> {
+        let path = &self.path;
+        if self.read_only {
+            bail!("Cannot write to a log file opened as read-only");
+        }
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.permissions().readonly() => {
+                bail!("The log file at `{}` is read-only.", path.display())
+            }
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).wrap_err(eyre!("Failed to read log file at `{}`", path.display())),
+        }
+    }
+
+    /// The path of a file that goes along with the log file, named like it
+    /// with `suffix` added.
+    pub fn sidecar_path(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Copy the log file to the first backup, moving older backups along and
+    /// getting rid of the oldest one if there are too many.
+    fn back_up(&self) -> eyre::Result<()> {
+        if self.backups == 0 {
+            return Ok(());
+        }
+        let oldest = self.backup_path(self.backups);
+        match fs::remove_file(&oldest) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).wrap_err(eyre!("Failed to remove backup at `{}`", oldest.display()))
+            }
+            _ => {}
+        }
+        for number in (1..self.backups).rev() {
+            let from = self.backup_path(number);
+            if from.is_file() {
+                let to = self.backup_path(number + 1);
+                fs::rename(&from, &to)
+                    .wrap_err(eyre!("Failed to move backup to `{}`", to.display()))?;
+            }
+        }
+        let to = self.backup_path(1);
+        fs::copy(&self.path, &to)
+            .wrap_err(eyre!("Failed to back up log file to `{}`", to.display()))?;
+        Ok(())
+    }
+
+    fn backup_path(&self, number: usize) -> PathBuf {
+        self.sidecar_path(&format!(".bak.{}", number))
+    }
+
+    /// All backups that currently exist, newest first.
+    pub fn backups(&self) -> eyre::Result<Vec<Backup>> {
+        let mut backups = vec![];
+        // Look past the number of backups we keep, in case more were kept
+        // before.
+        for number in 1.. {
+            let path = self.backup_path(number);
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => {
+                    return Err(e).wrap_err(eyre!("Failed to read backup at `{}`", path.display()))
+                }
+            };
+            backups.push(Backup {
+                number,
+                modified: metadata.modified()?,
+                log: self.read_backup(number),
+            });
+        }
+        Ok(backups)
+    }
+
+    /// Read one of the backups, as numbered by [`LogFile::backups`].
+    pub fn read_backup(&self, number: usize) -> eyre::Result<Log> {
+        let path = self.backup_path(number);
+        if !path.is_file() {
+            bail!("There is no backup {}.", number);
+        }
+        let (_, log) =
+            read(&path).wrap_err(eyre!("Failed to read backup at `{}`", path.display()))?;
+        Ok(log)
+    }
+
+    /// Let go of the lock, e.g. while waiting on the user for a long time.
+    /// Call [`LogFile::relock`] before writing the log again.
+    pub fn unlock(&mut self) -> eyre::Result<()> {
+        match self.lock {
+            Some(ref lock) => lock
+                .unlock()
+                .wrap_err(eyre!("Failed to unlock the log file")),
+            None => Ok(()),
+        }
+    }
+
+    /// Take the lock again after [`LogFile::unlock`], making sure nobody
+    /// changed the log file in the meantime.
+    pub fn relock(&mut self) -> eyre::Result<()> {
+        self.lock()?;
+        let (backend, log) = read(&self.path)?;
+        let changed = backend.map(|backend| backend.storage()) != self.stored_as()
+            || serde_json::to_value(&log)? != serde_json::to_value(&self.log)?;
+        if changed {
+            bail!(
+                "The log file at `{}` was changed by something else in the meantime.",
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn lock(&mut self) -> eyre::Result<()> {
+        let Some(ref file) = self.lock else {
+            return Ok(());
+        };
+        let try_lock = if self.read_only {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+        match try_lock {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) => {
+                info!("Waiting for another ttrk to be done with the log file...")
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err(eyre!("Failed to lock the log file"))
+            }
+        }
+        if self.read_only {
+            file.lock_shared()
+        } else {
+            file.lock()
+        }
+        .wrap_err(eyre!("Failed to lock the log file"))
+    }
+}
+
+/// Read the log file at `path`, however it is stored. A missing or empty log
+/// file is an empty log, with no backend.
+fn read(path: &Path) -> eyre::Result<(Option<Box<dyn Backend>>, Log)> {
+    let Some(storage) = Storage::detect(path)? else {
+        return Ok((None, Log::default()));
+    };
+    let mut backend = storage.backend();
+    let log = backend.read(path)?;
+    Ok((Some(backend), log))
+}
+
+/// Replace the file at `path` with one made by `create`, keeping its
+/// permissions.
+///
+/// The new file is made as a temporary file in the same directory, synced to
+/// disk, and then renamed over the old file, so if anything goes wrong partway
+/// through, the old file is left untouched.
+fn replace_with(path: &Path, create: impl FnOnce(&Path) -> eyre::Result<()>) -> eyre::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmpfile = NamedTempFile::new_in(dir).wrap_err(eyre!(
+        "Failed to create a temporary file in `{}`",
+        dir.display()
+    ))?;
+    match fs::metadata(path) {
+        Ok(metadata) => tmpfile
+            .as_file()
+            .set_permissions(metadata.permissions())
+            .wrap_err(eyre!(
+                "Failed to copy the permissions of `{}`",
+                path.display()
+            ))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).wrap_err(eyre!("Failed to read `{}`", path.display())),
+    }
+    create(tmpfile.path())
+        .and_then(|()| Ok(tmpfile.as_file().sync_all()?))
+        .wrap_err(eyre!("Failed to write to `{}`", path.display()))?;
+    tmpfile
+        .persist(path)
+        .wrap_err(eyre!("Failed to replace `{}`", path.display()))?;
+    // Make sure the rename itself makes it to disk. This is not possible (or
+    // needed) everywhere, so it is fine if it fails.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Replace the file at `path` with `content`, keeping its permissions. See
+/// [`replace_with`].
+pub fn replace_file(path: &Path, content: &str) -> eyre::Result<()> {
+    replace_with(path, |path| Ok(fs::write(path, content)?))
+}
+
+/// A backup of the log file.
+pub struct Backup {
+    pub number: usize,
+    pub modified: SystemTime,
+    pub log: eyre::Result<Log>,
+}
+
+/// The log as one JSON document.
+struct Document;
+
+/// A log as it is written to a JSON document.
+#[derive(Serialize)]
+struct Versioned<'a> {
+    version: u64,
+    #[serde(flatten)]
+    log: &'a Log,
+}
+
+impl Backend for Document {
+    fn storage(&self) -> Storage {
+        Storage::Json
+    }
+
+    fn read(&mut self, path: &Path) -> eyre::Result<Log> {
+        let content = fs::read_to_string(path)
+            .wrap_err(eyre!("Failed to read log file at `{}`", path.display()))?;
+        let value = serde_json::from_str(&content)
+            .map_err(|e| repair::parse_error(repair::error_position(&content, &e), e))?;
+        let value = migrations::migrate(value)?;
+        serde_json::from_value(value).map_err(|e| {
+            // Parse it again straight from the file to find where the problem
+            // is.
+            match serde_json::from_str::<Log>(&content) {
+                Err(e) => repair::parse_error(repair::error_position(&content, &e), e),
+                Ok(_) => eyre::Report::new(e).wrap_err(eyre!("Failed to parse log file")),
+            }
+        })
+    }
+
+    fn update(&mut self, _path: &Path, _before: &Log, _after: &Log) -> eyre::Result<bool> {
+        Ok(false)
+    }
+
+    fn create(&mut self, path: &Path, log: &Log) -> eyre::Result<()> {
+        let content = serde_json::to_string(&Versioned {
+            version: migrations::VERSION,
+            log,
+        })?;
+        Ok(fs::write(path, content)?)
+    }
+}
+
+/// If `path` is a symlink, find the file it points to, so that replacing the
+/// log file does not replace the symlink.
+fn resolve_symlinks(path: &Path) -> eyre::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(path.to_owned()),
+        Err(e) => Err(e).wrap_err(eyre!("Failed to find log file at `{}`", path.display())),
+    }
+}